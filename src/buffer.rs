@@ -0,0 +1,200 @@
+//! Text of the file being edited.
+//!
+//! The text lives in a rope, so editing it and finding a line take
+//! O(log n) however big the file is. Positions are `(line, byte offset)`
+//! pairs, like the cursor. Every line, the last one included, ends in `\n`.
+//!
+//! Files too big for that are viewed read-only through a `MappedFile`.
+
+use crate::mapped::MappedFile;
+use ropey::Rope;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Write};
+
+/// Cloning is cheap, the copy shares the rope's nodes or the mapped file.
+#[derive(Clone)]
+pub struct Buffer {
+    text: Text,
+}
+
+#[derive(Clone)]
+enum Text {
+    Rope(Rope),
+    Mapped(MappedFile),
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Buffer {
+            text: Text::Rope(Rope::new()),
+        }
+    }
+
+    /// Buffer holding `text`, split into lines the way `str::lines` does.
+    ///
+    /// The rope is built in a single pass, however many lines there are.
+    pub fn from_text(text: &str) -> Self {
+        let mut text = if text.contains("\r\n") {
+            Rope::from_str(&text.replace("\r\n", "\n"))
+        } else {
+            Rope::from_str(text)
+        };
+        let len = text.len_chars();
+        if len > 0 && text.char(len - 1) != '\n' {
+            text.insert_char(len, '\n');
+        }
+
+        Buffer {
+            text: Text::Rope(text),
+        }
+    }
+
+    /// Read-only buffer showing `file` without loading it.
+    pub fn map(file: &File) -> io::Result<Self> {
+        Ok(Buffer {
+            text: Text::Mapped(MappedFile::new(file)?),
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.text, Text::Mapped(_))
+    }
+
+    /// Whether `len` is final, the lines of a mapped file take a while to
+    /// count.
+    pub fn is_complete(&self) -> bool {
+        match &self.text {
+            Text::Rope(_) => true,
+            Text::Mapped(file) => file.is_complete(),
+        }
+    }
+
+    /// The rope to edit, read-only buffers have none.
+    fn rope(&mut self) -> &mut Rope {
+        match &mut self.text {
+            Text::Rope(rope) => rope,
+            Text::Mapped(_) => panic!("editing a read-only buffer"),
+        }
+    }
+
+    /// Number of lines.
+    pub fn len(&self) -> usize {
+        match &self.text {
+            // The rope counts the empty line after the final `\n` as well.
+            Text::Rope(rope) => rope.len_lines() - 1,
+            Text::Mapped(file) => file.len(),
+        }
+    }
+
+    /// Line `y` without its `\n`.
+    ///
+    /// The line past the end, where the cursor may sit, is empty.
+    pub fn line(&self, y: usize) -> String {
+        match &self.text {
+            Text::Rope(rope) => {
+                let mut line = rope.line(y).to_string();
+                line.pop();
+                line
+            }
+            Text::Mapped(file) => file.lines_from(y).next().unwrap_or_default().into_owned(),
+        }
+    }
+
+    /// Length of line `y` in bytes, without its `\n`.
+    pub fn line_len(&self, y: usize) -> usize {
+        match &self.text {
+            Text::Rope(rope) => rope.line(y).len_bytes().saturating_sub(1),
+            Text::Mapped(_) => self.line(y).len(),
+        }
+    }
+
+    /// Lines from `y` on, without their `\n`.
+    ///
+    /// Quicker than looking up one line after the other.
+    pub fn lines_from(&self, y: usize) -> Box<dyn Iterator<Item = Cow<'_, str>> + '_> {
+        match &self.text {
+            Text::Rope(rope) => Box::new(
+                rope.lines_at(y)
+                    .take(self.len().saturating_sub(y))
+                    .map(|line| line.slice(..line.len_chars() - 1).into()),
+            ),
+            Text::Mapped(file) => Box::new(file.lines_from(y)),
+        }
+    }
+
+    /// First line containing `query`, looking at line `start` first and
+    /// moving on forward or backward, wrapping around at either end.
+    pub fn find_line(&self, query: &str, start: usize, forward: bool) -> Option<usize> {
+        if let Text::Mapped(file) = &self.text {
+            return file.find_line(query, start, forward);
+        }
+
+        let len = self.len();
+        (0..len)
+            .map(|i| {
+                if forward {
+                    (start + i) % len
+                } else {
+                    (start + len - i) % len
+                }
+            })
+            .find(|&y| self.line(y).contains(query))
+    }
+
+    fn char_idx(rope: &Rope, (y, x): (usize, usize)) -> usize {
+        rope.byte_to_char(rope.line_to_byte(y) + x)
+    }
+
+    /// Insert `text`, which may span several lines, at a position within
+    /// an existing line or at the start of the line past the end.
+    pub fn insert(&mut self, pos: (usize, usize), text: &str) {
+        let rope = self.rope();
+        let idx = Self::char_idx(rope, pos);
+        rope.insert(idx, text);
+    }
+
+    /// Remove the text between two positions, joining their lines.
+    pub fn remove(&mut self, start: (usize, usize), end: (usize, usize)) {
+        let rope = self.rope();
+        let (start, end) = (Self::char_idx(rope, start), Self::char_idx(rope, end));
+        rope.remove(start..end);
+    }
+
+    /// Text between two positions.
+    pub fn slice(&self, (sy, sx): (usize, usize), (ey, ex): (usize, usize)) -> String {
+        match &self.text {
+            Text::Rope(rope) => {
+                let (start, end) = (
+                    Self::char_idx(rope, (sy, sx)),
+                    Self::char_idx(rope, (ey, ex)),
+                );
+                rope.slice(start..end).to_string()
+            }
+            Text::Mapped(file) => {
+                let lines: Vec<_> = file.lines_from(sy).take(ey + 1 - sy).collect();
+                let text = lines.join("\n");
+                let end = text.len() - (lines[lines.len() - 1].len() - ex);
+                text[sx..end].to_string()
+            }
+        }
+    }
+
+    /// Write the whole text to `out`, without first making a `String` of it.
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match &self.text {
+            Text::Rope(rope) => rope
+                .chunks()
+                .try_for_each(|chunk| out.write_all(chunk.as_bytes())),
+            Text::Mapped(file) => out.write_all(file.text().as_bytes()),
+        }
+    }
+
+    /// The whole text, as it is saved.
+    pub fn text(&self) -> String {
+        match &self.text {
+            Text::Rope(rope) => rope.to_string(),
+            Text::Mapped(file) => file.text().into_owned(),
+        }
+    }
+}
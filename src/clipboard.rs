@@ -0,0 +1,131 @@
+//! Host clipboard access through OSC 52.
+//!
+//! The terminal owns the clipboard, so copying works over SSH as well.
+//! Inside tmux the sequences are wrapped for passthrough to the outer
+//! terminal.
+
+use std::env;
+
+/// Ask the terminal for the contents of the clipboard.
+pub const OSC52_QUERY: &str = "\x1b]52;c;?\x07";
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Decode base64, ignoring padding. Returns `None` on invalid input.
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut n: u32 = 0;
+    let mut bits = 0;
+
+    for c in text.bytes().filter(|&c| c != b'=') {
+        let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        n = n << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+
+    Some(out)
+}
+
+/// Sequence that puts `text` on the clipboard.
+///
+/// Returns `None` when the encoded payload is longer than `max` bytes, as
+/// terminals silently drop sequences above their own limit.
+pub fn osc52_copy(text: &str, max: usize) -> Option<String> {
+    let payload = base64_encode(text.as_bytes());
+    if payload.len() > max {
+        return None;
+    }
+
+    Some(passthrough(&format!("\x1b]52;c;{}\x07", payload)))
+}
+
+/// Clipboard contents from the terminal's reply to `OSC52_QUERY`, with the
+/// terminator already stripped.
+pub fn parse_osc52_reply(reply: &[u8]) -> Option<String> {
+    let reply = String::from_utf8_lossy(reply);
+    let start = reply.find("\x1b]52;")?;
+    let payload = reply[start..].splitn(3, ';').nth(2)?;
+    let payload = payload.trim_end_matches('\x1b');
+    let data = base64_decode(payload)?;
+
+    Some(String::from_utf8_lossy(&data).to_string())
+}
+
+/// Wrap `seq` so tmux hands it on to the terminal it is running in.
+fn passthrough(seq: &str) -> String {
+    if env::var_os("TMUX").is_some() {
+        format!("\x1bPtmux;{}\x1b\\", seq.replace('\x1b', "\x1b\x1b"))
+    } else {
+        seq.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trips() {
+        for (data, encoded) in &[
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"fooba", "Zm9vYmE="),
+            (b"foobar", "Zm9vYmFy"),
+            (b"\xff\x00\xfe", "/wD+"),
+        ] {
+            assert_eq!(base64_encode(data), *encoded);
+            assert_eq!(base64_decode(encoded).as_deref(), Some(*data));
+        }
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+
+    #[test]
+    fn parses_replies_with_either_terminator() {
+        // The BEL, or the backslash of ESC \, is gone already.
+        assert_eq!(parse_osc52_reply(b"\x1b]52;c;aGk=").as_deref(), Some("hi"));
+        assert_eq!(
+            parse_osc52_reply(b"\x1b]52;c;aGk=\x1b").as_deref(),
+            Some("hi")
+        );
+        assert_eq!(parse_osc52_reply(b"\x1b]52;c;").as_deref(), Some(""));
+        assert_eq!(parse_osc52_reply(b"\x1b]52;c"), None);
+        assert_eq!(parse_osc52_reply(b"\x1b]11;rgb:0/0/0"), None);
+    }
+
+    #[test]
+    fn copy_refuses_payloads_over_the_limit() {
+        let copy = osc52_copy("hello", 8).unwrap();
+        assert!(copy.contains("\x1b]52;c;aGVsbG8=\x07"));
+        assert_eq!(osc52_copy("hello", 7), None);
+    }
+}
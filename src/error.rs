@@ -0,0 +1,52 @@
+//! Errors reported to the user instead of aborting the editor.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum EditorError {
+    /// I/O on a file, along with its name.
+    File(String, io::Error),
+    /// Writing a file stopped after the first of the given number of bytes;
+    /// the original file was left alone.
+    PartialWrite(String, usize, usize, io::Error),
+    /// The terminal could not be set up, read or written.
+    Terminal(io::Error),
+    /// A system call outside of plain I/O failed.
+    Sys(nix::Error),
+}
+
+pub type Result<T> = std::result::Result<T, EditorError>;
+
+impl fmt::Display for EditorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditorError::File(name, e) => write!(f, "{}: {}", name, e),
+            EditorError::PartialWrite(name, written, total, e) => write!(
+                f,
+                "{}: only {} of {} bytes written, file left unchanged: {}",
+                name, written, total, e
+            ),
+            EditorError::Terminal(e) => write!(f, "terminal: {}", e),
+            EditorError::Sys(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for EditorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EditorError::File(_, e)
+            | EditorError::PartialWrite(_, _, _, e)
+            | EditorError::Terminal(e) => Some(e),
+            EditorError::Sys(e) => Some(e),
+        }
+    }
+}
+
+impl From<nix::Error> for EditorError {
+    fn from(e: nix::Error) -> Self {
+        EditorError::Sys(e)
+    }
+}
@@ -0,0 +1,500 @@
+//! Undo and redo.
+//!
+//! Every edit is recorded as the text inserted into or removed from the
+//! buffer at a position, which is all it takes to do it again or take it
+//! back. The edits of one command make up a `Change`, and characters typed
+//! or deleted one after the other are joined into a single change.
+//!
+//! Changes are kept as a tree of the states they lead to: editing after an
+//! undo starts a new branch instead of dropping what was undone, so every
+//! state the buffer has been in can be gone back to.
+
+use crate::buffer::Buffer;
+use std::time::SystemTime;
+
+/// Position in the buffer, `(line, byte offset)`.
+type Pos = (usize, usize);
+
+pub enum Edit {
+    Insert(Pos, String),
+    Remove(Pos, String),
+}
+
+impl Edit {
+    /// Where `text` put at `at` ends.
+    fn end((y, x): Pos, text: &str) -> Pos {
+        match text.rfind('\n') {
+            Some(i) => (y + text.matches('\n').count(), text.len() - i - 1),
+            None => (y, x + text.len()),
+        }
+    }
+
+    fn apply(&self, buffer: &mut Buffer) {
+        match self {
+            Edit::Insert(at, text) => buffer.insert(*at, text),
+            Edit::Remove(at, text) => buffer.remove(*at, Self::end(*at, text)),
+        }
+    }
+
+    fn revert(&self, buffer: &mut Buffer) {
+        match self {
+            Edit::Insert(at, text) => buffer.remove(*at, Self::end(*at, text)),
+            Edit::Remove(at, text) => buffer.insert(*at, text),
+        }
+    }
+
+    fn line(&self) -> usize {
+        match self {
+            Edit::Insert((y, _), _) | Edit::Remove((y, _), _) => *y,
+        }
+    }
+}
+
+/// What a change was made by, which decides whether the next change may
+/// be joined to it.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Group {
+    /// Characters typed one after the other.
+    Typing,
+    /// Characters deleted one after the other.
+    Deleting,
+    /// A command undone on its own, such as a paste or a new line.
+    Command,
+}
+
+struct Change {
+    group: Group,
+    edits: Vec<Edit>,
+    /// Cursor before and after the change.
+    before: Pos,
+    after: Pos,
+}
+
+impl Change {
+    /// First line the change touches.
+    fn line(&self) -> usize {
+        self.edits
+            .iter()
+            .map(Edit::line)
+            .min()
+            .unwrap_or(self.before.0)
+    }
+}
+
+/// A state the buffer has been in, reached from its parent by `change`.
+struct Node {
+    parent: usize,
+    children: Vec<usize>,
+    /// Child that redo goes to, the one visited last.
+    next: Option<usize>,
+    change: Change,
+    /// When the change was made.
+    time: SystemTime,
+}
+
+/// A state of the buffer as listed in the history panel.
+pub struct State {
+    pub id: usize,
+    /// Number of branches off the first line of history it is on.
+    pub depth: usize,
+    pub time: SystemTime,
+    pub current: bool,
+    pub saved: bool,
+}
+
+pub struct History {
+    /// Every state ever reached, numbered in the order they were made.
+    /// The first one is the buffer as it was loaded.
+    nodes: Vec<Node>,
+    current: usize,
+    /// State last written to disk, `None` when none of them is.
+    saved: Option<usize>,
+    /// Whether the current change may be carried on by the next command.
+    open: bool,
+}
+
+impl History {
+    /// History of a buffer that was just loaded.
+    pub fn new() -> Self {
+        History {
+            nodes: vec![Node {
+                parent: 0,
+                children: Vec::new(),
+                next: None,
+                change: Change {
+                    group: Group::Command,
+                    edits: Vec::new(),
+                    before: (0, 0),
+                    after: (0, 0),
+                },
+                time: SystemTime::now(),
+            }],
+            current: 0,
+            saved: Some(0),
+            open: false,
+        }
+    }
+
+    /// Start recording the edits of a command, with the cursor at `cursor`.
+    ///
+    /// Typing or deleting right where the last change of the same kind left
+    /// the cursor carries that change on. Otherwise the command starts a new
+    /// state, branching off when there is something to redo.
+    pub fn begin(&mut self, group: Group, cursor: Pos) {
+        let last = &self.nodes[self.current].change;
+        let carry_on =
+            self.open && group != Group::Command && last.group == group && last.after == cursor;
+        if !carry_on {
+            let id = self.nodes.len();
+            self.nodes[self.current].children.push(id);
+            self.nodes.push(Node {
+                parent: self.current,
+                children: Vec::new(),
+                next: None,
+                change: Change {
+                    group,
+                    edits: Vec::new(),
+                    before: cursor,
+                    after: cursor,
+                },
+                time: SystemTime::now(),
+            });
+            self.current = id;
+        }
+    }
+
+    /// Record an edit already made to the buffer, between `begin` and `end`.
+    pub fn record(&mut self, edit: Edit) {
+        self.nodes[self.current].change.edits.push(edit);
+    }
+
+    /// Finish the command, leaving the cursor at `cursor`.
+    pub fn end(&mut self, cursor: Pos) {
+        let node = &mut self.nodes[self.current];
+        if node.change.edits.is_empty() {
+            // Nothing changed, forget about the state.
+            let parent = node.parent;
+            self.nodes.pop();
+            self.nodes[parent].children.pop();
+            self.current = parent;
+            return;
+        }
+
+        node.change.after = cursor;
+        node.time = SystemTime::now();
+        let parent = node.parent;
+        self.nodes[parent].next = Some(self.current);
+        self.open = true;
+    }
+
+    /// Take back the change that led to the current state.
+    ///
+    /// Returns the first line it touched and where to put the cursor, or
+    /// `None` when there is nothing to undo.
+    pub fn undo(&mut self, buffer: &mut Buffer) -> Option<(usize, Pos)> {
+        if self.current == 0 {
+            return None;
+        }
+        let node = &self.nodes[self.current];
+        for edit in node.change.edits.iter().rev() {
+            edit.revert(buffer);
+        }
+        let moved = (node.change.line(), node.change.before);
+        let parent = node.parent;
+        self.nodes[parent].next = Some(self.current);
+        self.current = parent;
+        self.open = false;
+        Some(moved)
+    }
+
+    /// Make the change last taken back again, like `undo`.
+    pub fn redo(&mut self, buffer: &mut Buffer) -> Option<(usize, Pos)> {
+        let next = self.nodes[self.current].next?;
+        let change = &self.nodes[next].change;
+        for edit in &change.edits {
+            edit.apply(buffer);
+        }
+        let moved = (change.line(), change.after);
+        self.current = next;
+        self.open = false;
+        Some(moved)
+    }
+
+    /// Take the buffer to state `id`, undoing back to the closest state on
+    /// the way and redoing from there, like `undo`.
+    ///
+    /// Returns `None` when the buffer is in that state already.
+    pub fn goto(&mut self, id: usize, buffer: &mut Buffer) -> Option<(usize, Pos)> {
+        let mut path = vec![id];
+        while path[path.len() - 1] != 0 {
+            path.push(self.nodes[path[path.len() - 1]].parent);
+        }
+
+        let mut moved = None;
+        let mut merge = |(line, cursor): (usize, Pos)| {
+            moved = Some(match moved {
+                Some((first, _)) => (line.min(first), cursor),
+                None => (line, cursor),
+            })
+        };
+        while !path.contains(&self.current) {
+            merge(self.undo(buffer)?);
+        }
+        let fork = path.iter().position(|&id| id == self.current).unwrap();
+        for &id in path[..fork].iter().rev() {
+            self.nodes[self.current].next = Some(id);
+            merge(self.redo(buffer)?);
+        }
+        moved
+    }
+
+    /// Step to the state made just before or after the current one, which
+    /// may be on another branch.
+    pub fn step(&mut self, forward: bool, buffer: &mut Buffer) -> Option<(usize, Pos)> {
+        let id = if forward {
+            self.current + 1
+        } else {
+            self.current.checked_sub(1)?
+        };
+        if id >= self.nodes.len() {
+            return None;
+        }
+        self.goto(id, buffer)
+    }
+
+    /// Switch to the next or previous branch at the closest fork above the
+    /// current state, to the state that branch was last left in.
+    pub fn switch_branch(&mut self, forward: bool, buffer: &mut Buffer) -> Option<(usize, Pos)> {
+        let mut id = self.current;
+        while id != 0 {
+            let siblings = &self.nodes[self.nodes[id].parent].children;
+            if siblings.len() > 1 {
+                let i = siblings.iter().position(|&sibling| sibling == id).unwrap();
+                let n = siblings.len();
+                let mut target = siblings[if forward {
+                    (i + 1) % n
+                } else {
+                    (i + n - 1) % n
+                }];
+                while let Some(next) = self.nodes[target].next {
+                    target = next;
+                }
+                return self.goto(target, buffer);
+            }
+            id = self.nodes[id].parent;
+        }
+        None
+    }
+
+    /// State made last at or before `time`, the loaded one when there is
+    /// none.
+    pub fn state_at(&self, time: SystemTime) -> usize {
+        self.nodes
+            .iter()
+            .rposition(|node| node.time <= time)
+            .unwrap_or(0)
+    }
+
+    /// When the change leading to state `id` was made.
+    pub fn time(&self, id: usize) -> SystemTime {
+        self.nodes[id].time
+    }
+
+    /// State last written to disk, if any.
+    pub fn saved(&self) -> Option<usize> {
+        self.saved
+    }
+
+    /// Remember the current state as the one on disk.
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.current);
+        self.open = false;
+    }
+
+    /// Forget about the state on disk, for a buffer that was loaded from
+    /// elsewhere, like a swap file.
+    pub fn mark_unsaved(&mut self) {
+        self.saved = None;
+    }
+
+    /// Whether the buffer is back to what was last saved.
+    pub fn is_saved(&self) -> bool {
+        self.saved == Some(self.current)
+    }
+
+    /// All states, each branch listed after the one it forks off from, and
+    /// indented one level more.
+    pub fn states(&self) -> Vec<State> {
+        let mut states = Vec::new();
+        let mut stack = vec![(0, 0)];
+        while let Some((id, depth)) = stack.pop() {
+            let node = &self.nodes[id];
+            states.push(State {
+                id,
+                depth,
+                time: node.time,
+                current: id == self.current,
+                saved: self.saved == Some(id),
+            });
+            for (i, &child) in node.children.iter().enumerate().rev() {
+                stack.push((child, if i == 0 { depth } else { depth + 1 }));
+            }
+        }
+        states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Type `text` at `at` as one command.
+    fn insert(history: &mut History, buffer: &mut Buffer, group: Group, at: Pos, text: &str) {
+        history.begin(group, at);
+        buffer.insert(at, text);
+        history.record(Edit::Insert(at, text.to_string()));
+        history.end(Edit::end(at, text));
+    }
+
+    #[test]
+    fn typing_run_is_one_change() {
+        let (mut history, mut buffer) = (History::new(), Buffer::new());
+        insert(&mut history, &mut buffer, Group::Typing, (0, 0), "a");
+        insert(&mut history, &mut buffer, Group::Typing, (0, 1), "b");
+        assert_eq!(buffer.text(), "ab");
+        assert_eq!(history.states().len(), 2);
+
+        assert_eq!(history.undo(&mut buffer), Some((0, (0, 0))));
+        assert_eq!(buffer.text(), "");
+        assert_eq!(history.redo(&mut buffer), Some((0, (0, 2))));
+        assert_eq!(buffer.text(), "ab");
+    }
+
+    #[test]
+    fn commands_and_moves_break_runs() {
+        let (mut history, mut buffer) = (History::new(), Buffer::new());
+        insert(&mut history, &mut buffer, Group::Command, (0, 0), "a");
+        insert(&mut history, &mut buffer, Group::Command, (0, 1), "b");
+        insert(&mut history, &mut buffer, Group::Typing, (0, 0), "c");
+        assert_eq!(buffer.text(), "cab");
+        assert_eq!(history.states().len(), 4);
+
+        history.undo(&mut buffer);
+        assert_eq!(buffer.text(), "ab");
+    }
+
+    #[test]
+    fn end_drops_empty_changes() {
+        let (mut history, mut buffer) = (History::new(), Buffer::new());
+        history.begin(Group::Deleting, (0, 0));
+        history.end((0, 0));
+        assert_eq!(history.states().len(), 1);
+        assert!(history.is_saved());
+        assert_eq!(history.undo(&mut buffer), None);
+
+        insert(&mut history, &mut buffer, Group::Typing, (0, 0), "a");
+        assert_eq!(history.states()[1].id, 1);
+    }
+
+    #[test]
+    fn editing_after_undo_branches() {
+        let (mut history, mut buffer) = (History::new(), Buffer::new());
+        insert(&mut history, &mut buffer, Group::Typing, (0, 0), "a");
+        history.undo(&mut buffer);
+        insert(&mut history, &mut buffer, Group::Typing, (0, 0), "b");
+        assert_eq!(buffer.text(), "b");
+
+        let states: Vec<_> = history
+            .states()
+            .iter()
+            .map(|state| (state.id, state.depth, state.current))
+            .collect();
+        assert_eq!(states, [(0, 0, false), (1, 0, false), (2, 1, true)]);
+
+        assert!(history.switch_branch(true, &mut buffer).is_some());
+        assert_eq!(buffer.text(), "a");
+        assert!(history.switch_branch(true, &mut buffer).is_some());
+        assert_eq!(buffer.text(), "b");
+    }
+
+    #[test]
+    fn goto_crosses_branches() {
+        let (mut history, mut buffer) = (History::new(), Buffer::new());
+        insert(&mut history, &mut buffer, Group::Command, (0, 0), "a\n");
+        insert(&mut history, &mut buffer, Group::Command, (1, 0), "b\n");
+        history.undo(&mut buffer);
+        insert(&mut history, &mut buffer, Group::Command, (1, 0), "c\n");
+        assert_eq!(buffer.text(), "a\nc\n");
+
+        assert_eq!(history.goto(2, &mut buffer), Some((1, (2, 0))));
+        assert_eq!(buffer.text(), "a\nb\n");
+        assert_eq!(history.goto(2, &mut buffer), None);
+        assert_eq!(history.goto(0, &mut buffer), Some((0, (0, 0))));
+        assert_eq!(buffer.text(), "");
+
+        // Redo follows the branch visited last.
+        history.redo(&mut buffer);
+        history.redo(&mut buffer);
+        assert_eq!(buffer.text(), "a\nb\n");
+    }
+
+    #[test]
+    fn step_goes_in_the_order_states_were_made() {
+        let (mut history, mut buffer) = (History::new(), Buffer::new());
+        insert(&mut history, &mut buffer, Group::Command, (0, 0), "a");
+        history.undo(&mut buffer);
+        insert(&mut history, &mut buffer, Group::Command, (0, 0), "b");
+
+        assert!(history.step(false, &mut buffer).is_some());
+        assert_eq!(buffer.text(), "a");
+        assert!(history.step(false, &mut buffer).is_some());
+        assert_eq!(buffer.text(), "");
+        assert_eq!(history.step(false, &mut buffer), None);
+        history.step(true, &mut buffer);
+        history.step(true, &mut buffer);
+        assert_eq!(buffer.text(), "b");
+        assert_eq!(history.step(true, &mut buffer), None);
+    }
+
+    #[test]
+    fn state_at_finds_the_last_state_made_by_then() {
+        let (mut history, mut buffer) = (History::new(), Buffer::new());
+        let start = history.time(0);
+        insert(&mut history, &mut buffer, Group::Command, (0, 0), "a");
+        insert(&mut history, &mut buffer, Group::Command, (0, 1), "b");
+
+        assert_eq!(history.state_at(SystemTime::now()), 2);
+        assert_eq!(history.state_at(history.time(1)), 1);
+        assert_eq!(history.state_at(start - Duration::from_secs(60)), 0);
+    }
+
+    #[test]
+    fn saved_state_survives_undo_and_redo() {
+        let (mut history, mut buffer) = (History::new(), Buffer::new());
+        insert(&mut history, &mut buffer, Group::Typing, (0, 0), "a");
+        history.mark_saved();
+        assert!(history.is_saved());
+
+        // Saving ends the typing run.
+        insert(&mut history, &mut buffer, Group::Typing, (0, 1), "b");
+        assert!(!history.is_saved());
+        history.undo(&mut buffer);
+        assert!(history.is_saved());
+        assert_eq!(buffer.text(), "a");
+        history.undo(&mut buffer);
+        assert!(!history.is_saved());
+    }
+
+    #[test]
+    fn unsaved_buffer_is_never_clean() {
+        let (mut history, mut buffer) = (History::new(), Buffer::from_text("recovered"));
+        history.mark_unsaved();
+        assert!(!history.is_saved());
+
+        insert(&mut history, &mut buffer, Group::Typing, (0, 0), "a");
+        history.undo(&mut buffer);
+        assert!(!history.is_saved());
+        assert_eq!(history.saved(), None);
+    }
+}
@@ -1,29 +1,77 @@
-use std::collections::HashSet;
+use buffer::Buffer;
+use clipboard::OSC52_QUERY;
+use error::{EditorError, Result};
+use history::{Edit, Group, History};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd;
+use screen::Frame;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, OpenOptions};
 use std::io::{self, Read, Write};
 use std::iter::FromIterator;
+use std::ops::Range;
+use std::os::unix::fs::{self as unix_fs, MetadataExt, OpenOptionsExt};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::time::{Duration, SystemTime};
-use termios::*;
-
-// TODO Implement `Result`, remove `unwrap`.
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use terminal::{
+    Terminal, TtyTerminal, ENTER_SCREEN, KITTY_KEYBOARD_POP, KITTY_KEYBOARD_PUSH,
+    KITTY_KEYBOARD_QUERY, LEAVE_SCREEN,
+};
+use theme::{ColorSupport, Theme, THEMES};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+mod buffer;
+mod clipboard;
+mod error;
+mod history;
+mod mapped;
+mod screen;
+mod terminal;
+mod theme;
 
 const KILO_TAB_STOP: usize = 8;
 const KILO_QUIT_TIMES: usize = 3;
-
-/// Row stores information about characters in a row
+const KILO_SCROLL_LINES: usize = 3;
+/// Default limit on the base64 payload of an OSC 52 copy.
+const KILO_CLIPBOARD_MAX: usize = 100_000;
+const KILO_STATUS_MSG_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long changes may go without being written to the swap file.
+const KILO_SWAP_INTERVAL: Duration = Duration::from_secs(4);
+/// How often a swap file write in the background is checked on.
+const KILO_SWAP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Default size from which files are viewed read-only instead of loaded.
+const KILO_VIEW_THRESHOLD: u64 = 256 << 20;
+/// How often the line count is updated while a viewed file is indexed.
+const KILO_INDEX_INTERVAL: Duration = Duration::from_millis(250);
+/// Longest paste taken in, anything after it is dropped.
+const KILO_PASTE_MAX: usize = 64 << 20;
+/// Read timeouts in a row after which a paste is taken to be over, even
+/// though its end marker never came.
+const KILO_PASTE_TIMEOUTS: usize = 10;
+/// Columns taken by the undo history panel, its border included.
+const KILO_HISTORY_PANEL_WIDTH: usize = 24;
+
+/// Row is a line of the buffer as it is shown on screen
 ///
 /// Supports rendering tabs or spaces and syntax highlighting.
+/// Control characters are rendered in caret notation, `controls` keeps
+/// the byte ranges of `render` they occupy.
 #[derive(Default)]
 struct Row {
-    idx: usize,
     chars: String,
     render: String,
     hl: Vec<Highlight>,
     hl_open_comment: bool,
+    controls: Vec<Range<usize>>,
 }
 
 #[derive(Eq, PartialEq)]
@@ -42,20 +90,7 @@ enum Highlight {
     String,
     Number,
     Match,
-}
-
-impl From<Highlight> for i32 {
-    fn from(hl: Highlight) -> i32 {
-        match hl {
-            Highlight::Comment | Highlight::MLComment => 36,
-            Highlight::Keyword1 => 33,
-            Highlight::Keyword2 => 32,
-            Highlight::String => 35,
-            Highlight::Number => 31,
-            Highlight::Match => 34,
-            _ => 37,
-        }
-    }
+    Control,
 }
 
 /// Set bit flags
@@ -72,31 +107,61 @@ struct EditorConfig {
     screencols: usize,
     rowoff: usize,
     coloff: usize,
+    buffer: Buffer,
+    /// Rendered rows by line number, kept only for the lines on screen.
+    rows: HashMap<usize, Row>,
+    /// Number of lines as of the last refresh, taken once as the lines of
+    /// a viewed file keep coming in while it is indexed.
     numrows: usize,
-    rows: Vec<Row>,
-    term: Termios,
-    fd: RawFd,
+    /// Whether each line ends inside a multi-line comment, known for the
+    /// lines above the first one that changed or was never highlighted.
+    open_comments: Vec<bool>,
+    history: History,
+    history_panel: bool,
+    terminal: Box<dyn Terminal>,
     dirty: bool,
     quit_times: usize,
+    /// Set once the user asked to leave, `editor_run` returns then.
+    quit: bool,
     filename: Option<String>,
     status_msg: String,
-    status_msg_time: SystemTime,
+    timers: Vec<(Instant, Timer)>,
+    signal_fd: Option<RawFd>,
     last_match: isize,
     direction: Direction,
     saved_hl_line: isize,
     saved_hl: Option<Vec<Highlight>>,
     hldb: Vec<EditorSyntax>,
     editor_syntax: Option<EditorSyntax>,
+    full_redraw: bool,
+    kitty_keyboard: bool,
+    /// Last title written to the terminal.
+    title: Option<String>,
+    clipboard: String,
+    clipboard_max: usize,
+    clipboard_read: bool,
+    /// Cleared when the swap file belongs to someone else or can't be written.
+    swap_enabled: bool,
+    /// Whether there are changes the swap file doesn't have yet.
+    swap_stale: bool,
+    /// Swap file write going on in the background.
+    swap_writer: Option<thread::JoinHandle<Result<()>>>,
+    view_threshold: u64,
+    front: Frame,
+    back: Frame,
+    drawn_rowoff: usize,
+    selection_anchor: Option<(usize, usize)>,
+    theme: usize,
+    color_support: ColorSupport,
 }
 
 impl EditorConfig {
-    fn new() -> Self {
-        let fd = io::stdin().as_raw_fd();
-        let mut term: Termios = Termios::from_fd(fd).unwrap();
-        tcgetattr(fd, &mut term).unwrap();
-
-        let (mut screenrows, screencols) = get_window_size().unwrap();
-        screenrows -= 2;
+    fn new(mut terminal: Box<dyn Terminal>) -> Result<Self> {
+        let (screenrows, screencols) = terminal
+            .size()
+            .ok_or_else(|| EditorError::Terminal(io::Error::other("can't get the window size")))?;
+        // However small the window, there is a line of text to draw.
+        let (screenrows, screencols) = (screenrows.saturating_sub(2).max(1), screencols.max(1));
 
         let c_filematch = vec!["c".to_string(), "h".to_string(), "cpp".to_string()];
         let c_keywords: Vec<String> = vec![
@@ -128,8 +193,7 @@ impl EditorConfig {
         .map(String::from)
         .collect();
 
-        let mut hldb = Vec::new();
-        hldb.push(EditorSyntax::new(
+        let hldb = vec![EditorSyntax::new(
             "c",
             HashSet::from_iter(c_filematch),
             c_keywords,
@@ -137,40 +201,71 @@ impl EditorConfig {
             "/*".to_string(),
             "*/".to_string(),
             HighlightFlag::Number as u8 | HighlightFlag::String as u8,
-        ));
+        )];
 
-        EditorConfig {
+        Ok(EditorConfig {
             cx: 0,
             cy: 0,
             rx: 0,
-            fd,
-            term,
+            terminal,
             rowoff: 0,
             coloff: 0,
-            rows: Vec::new(),
+            buffer: Buffer::new(),
+            rows: HashMap::new(),
             numrows: 0,
+            open_comments: Vec::new(),
+            history: History::new(),
+            history_panel: false,
             screenrows,
             screencols,
             dirty: false,
             quit_times: KILO_QUIT_TIMES,
+            quit: false,
             filename: None,
             status_msg: String::new(),
-            status_msg_time: SystemTime::now(),
+            timers: Vec::new(),
+            signal_fd: None,
             last_match: -1,
             direction: Direction::Forward,
             saved_hl_line: -1,
             saved_hl: None,
             hldb,
             editor_syntax: None,
-        }
+            full_redraw: true,
+            kitty_keyboard: false,
+            title: None,
+            clipboard: String::new(),
+            clipboard_max: env::var("KILO_CLIPBOARD_MAX")
+                .ok()
+                .and_then(|max| max.parse().ok())
+                .unwrap_or(KILO_CLIPBOARD_MAX),
+            clipboard_read: env::var_os("KILO_CLIPBOARD_READ").is_some(),
+            swap_enabled: true,
+            swap_stale: false,
+            swap_writer: None,
+            view_threshold: env::var("KILO_VIEW_THRESHOLD")
+                .ok()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(KILO_VIEW_THRESHOLD),
+            front: Frame::new(0, 0),
+            back: Frame::new(0, 0),
+            drawn_rowoff: 0,
+            selection_anchor: None,
+            theme: env::var("KILO_THEME")
+                .ok()
+                .and_then(|name| Theme::find(&name))
+                .unwrap_or(0),
+            color_support: ColorSupport::detect(),
+        })
     }
 }
 
 /// EditorKey represents all Keys pressed
-#[derive(Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq)]
 enum EditorKey {
     Char(char),
     Ctrl(char),
+    Tab,
     ArrowLeft,
     ArrowRight,
     ArrowUp,
@@ -183,6 +278,42 @@ enum EditorKey {
     EscapeSeq,
     CarriageReturn,
     Backspace,
+    Resize,
+    /// Nothing was pressed, but a timer fired and the screen may be stale.
+    Timer,
+    Paste(String),
+    MousePress(usize, usize),
+    MouseDrag(usize, usize),
+    MouseRelease(usize, usize),
+    WheelUp,
+    WheelDown,
+    InsertKey,
+    Function(u8),
+    /// A key pressed together with `KeyModifier` flags.
+    Modified(u8, Box<EditorKey>),
+    /// A sequence we don't know, which is ignored rather than taken for
+    /// the Escape key.
+    Unknown,
+}
+
+/// Work scheduled with `editor_set_timer`.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Timer {
+    /// Clear the status message.
+    StatusMessage,
+    /// Write the unsaved changes to the swap file.
+    Swap,
+    /// See whether the swap file write in the background is done.
+    SwapWritten,
+    /// Show how many lines of a viewed file were found so far.
+    Index,
+}
+
+/// Set bit flags, laid out like xterm's modifier parameter.
+enum KeyModifier {
+    Shift = 1 << 0,
+    Alt = 1 << 1,
+    Ctrl = 1 << 2,
 }
 
 #[derive(Clone)]
@@ -220,109 +351,235 @@ impl EditorSyntax {
 
 // *** Terminal ***
 
-fn get_window_size() -> Option<(usize, usize)> {
-    let mut winsize = libc::winsize {
-        ws_row: 0,
-        ws_col: 0,
-        ws_xpixel: 0,
-        ws_ypixel: 0,
-    };
-    let mut out = io::stdout();
+/// Set by the SIGWINCH handler, consumed by `editor_read_key`.
+static WINDOW_RESIZED: AtomicBool = AtomicBool::new(false);
 
-    unsafe {
-        if libc::ioctl(out.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize) == -1 {
-            if out.write(b"\x1b[999C\x1b[999B").unwrap() != 12 {
-                return None;
-            }
+/// Set by the SIGCONT handler, consumed by `editor_read_key`.
+static CONTINUED: AtomicBool = AtomicBool::new(false);
 
-            return get_cursor_position();
+/// Set by the SIGHUP and SIGTERM handler, consumed by `editor_read_key`.
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+/// Write end of the self-pipe that wakes up `editor_poll` on a signal.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_sigwinch(_: libc::c_int) {
+    WINDOW_RESIZED.store(true, Ordering::SeqCst);
+    wake_event_loop();
+}
+
+extern "C" fn handle_sigcont(_: libc::c_int) {
+    CONTINUED.store(true, Ordering::SeqCst);
+    wake_event_loop();
+}
+
+extern "C" fn handle_hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+    wake_event_loop();
+}
+
+fn wake_event_loop() {
+    let fd = SIGNAL_PIPE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe {
+            libc::write(fd, b"!".as_ptr() as *const libc::c_void, 1);
         }
     }
+}
 
-    Some((winsize.ws_row as usize, winsize.ws_col as usize))
+/// Install the signal handlers.
+///
+/// Returns the read end of the self-pipe the handlers write to.
+fn install_signal_handlers() -> nix::Result<RawFd> {
+    let (read_fd, write_fd) = unistd::pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+    SIGNAL_PIPE.store(write_fd, Ordering::SeqCst);
+
+    let handlers: [(Signal, extern "C" fn(libc::c_int)); 4] = [
+        (Signal::SIGWINCH, handle_sigwinch),
+        (Signal::SIGCONT, handle_sigcont),
+        (Signal::SIGHUP, handle_hangup),
+        (Signal::SIGTERM, handle_hangup),
+    ];
+    for (sig, handler) in handlers.iter() {
+        let action = SigAction::new(
+            SigHandler::Handler(*handler),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe {
+            signal::sigaction(*sig, &action)?;
+        }
+    }
+    Ok(read_fd)
 }
 
-fn get_cursor_position() -> Option<(usize, usize)> {
-    let mut out = io::stdout();
-    let mut inp = io::stdin();
-    if out.write(b"\x1b[6n").unwrap() != 4 {
-        None
-    } else {
-        let mut buf: [u8; 32] = [0; 32];
-        let mut i = 0;
-        let inp = &mut inp;
-
-        while i < 31 {
-            let mut handle = inp.take(1);
-            let mut b = [0 as u8; 1];
-            if handle.read(&mut b).unwrap() != 1 {
-                break;
-            }
-            buf[i] = b[0];
-            if buf[i] as char == 'R' {
-                break;
+/// Run `timer` once `delay` has passed, replacing an earlier one of the
+/// same kind.
+fn editor_set_timer(cfg: &mut EditorConfig, timer: Timer, delay: Duration) {
+    cfg.timers.retain(|(_, t)| *t != timer);
+    cfg.timers.push((Instant::now() + delay, timer));
+}
+
+fn editor_cancel_timer(cfg: &mut EditorConfig, timer: Timer) {
+    cfg.timers.retain(|(_, t)| *t != timer);
+}
+
+/// Fire the timers that are due, returns whether any did.
+fn editor_run_timers(cfg: &mut EditorConfig) -> bool {
+    let now = Instant::now();
+    let (due, pending) = cfg.timers.iter().partition(|(at, _)| *at <= now);
+    cfg.timers = pending;
+
+    for (_, timer) in due.iter() {
+        match timer {
+            Timer::StatusMessage => cfg.status_msg.clear(),
+            Timer::Swap => editor_write_swap(cfg),
+            Timer::SwapWritten => match &cfg.swap_writer {
+                Some(writer) if !writer.is_finished() => {
+                    editor_set_timer(cfg, Timer::SwapWritten, KILO_SWAP_POLL_INTERVAL);
+                }
+                _ => editor_wait_swap(cfg),
+            },
+            Timer::Index => {
+                if !cfg.buffer.is_complete() {
+                    editor_set_timer(cfg, Timer::Index, KILO_INDEX_INTERVAL);
+                }
             }
-            i += 1;
         }
+    }
+    !due.is_empty()
+}
 
-        buf[i] = 0;
+/// Sleep until the terminal has input, a signal arrives or the next timer
+/// is due.
+///
+/// Returns whether the terminal has input to read.
+fn editor_poll(cfg: &mut EditorConfig) -> bool {
+    let input_fd = match cfg.terminal.input_fd() {
+        Some(fd) => fd,
+        None => return true,
+    };
+    let timeout = cfg
+        .timers
+        .iter()
+        .map(|(at, _)| at.saturating_duration_since(Instant::now()))
+        .min()
+        .map_or(-1, |wait| {
+            wait.as_millis().min(i32::MAX as u128) as libc::c_int + 1
+        });
+
+    let mut fds = vec![PollFd::new(input_fd, PollFlags::POLLIN)];
+    if let Some(fd) = cfg.signal_fd {
+        fds.push(PollFd::new(fd, PollFlags::POLLIN));
+    }
+    match poll(&mut fds, timeout) {
+        Ok(_) => (),
+        Err(nix::Error::Sys(Errno::EINTR)) => return false,
+        Err(e) => editor_die(cfg, e.into()),
+    }
 
-        if buf[0] as char != '\x1b' || buf[1] as char != '[' {
-            return None;
+    if let Some(fd) = cfg.signal_fd {
+        let mut buf = [0_u8; 64];
+        while let Ok(n) = unistd::read(fd, &mut buf) {
+            if n == 0 {
+                break;
+            }
         }
-        Some((0, 0))
     }
+    fds[0].revents().is_some_and(|ev| !ev.is_empty())
 }
 
-fn enable_raw_mode(cfg: &EditorConfig) -> Result<(), io::Error> {
-    let mut raw = cfg.term;
-    raw.c_iflag &= !(BRKINT | INPCK | ISTRIP | ICRNL | IXON);
-    raw.c_oflag &= !(OPOST);
-    raw.c_cflag |= CS8;
-    raw.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
-    raw.c_cc[VMIN] = 0;
-    raw.c_cc[VTIME] = 1;
-    tcsetattr(cfg.fd, TCSAFLUSH, &mut raw)?;
-    Ok(())
-}
+// *** Syntax Highlighting ***
 
-fn disable_raw_mode(raw: &Termios) -> Result<(), io::Error> {
-    let mut raw = raw;
-    tcsetattr(io::stdin().as_raw_fd(), TCSAFLUSH, &mut raw)?;
+/// Whether line `y` starts inside a multi-line comment.
+///
+/// Lines above it that were never highlighted are scanned first, once.
+fn editor_in_comment(cfg: &mut EditorConfig, y: usize) -> bool {
+    let syntax = match cfg.editor_syntax.as_ref() {
+        Some(syntax) => syntax,
+        None => return false,
+    };
 
-    Ok(())
+    let known = cfg.open_comments.len();
+    let mut in_comment = cfg.open_comments.last().copied().unwrap_or(false);
+    for chars in cfg.buffer.lines_from(known).take(y.saturating_sub(known)) {
+        // Tabs and control characters change the text that is highlighted,
+        // 0xc2 starts the C1 controls (and some printable characters).
+        in_comment = if chars.bytes().any(|b| b < 0x20 || b == 0x7f || b == 0xc2) {
+            let row = editor_render_row(chars.into_owned());
+            syntax_ends_in_comment(syntax, in_comment, &row.render)
+        } else {
+            syntax_ends_in_comment(syntax, in_comment, &chars)
+        };
+        cfg.open_comments.push(in_comment);
+    }
+    y > 0 && cfg.open_comments[y - 1]
 }
 
-// *** Syntax Highlighting ***
+/// Whether `text` ends inside a multi-line comment, given whether it
+/// starts in one.
+///
+/// Follows the comment and string rules of `apply_syntax` without
+/// highlighting anything, to catch up quickly on lines that aren't shown.
+fn syntax_ends_in_comment(syntax: &EditorSyntax, mut in_comment: bool, text: &str) -> bool {
+    let scs = syntax.singleline_comment_start.as_bytes();
+    let mcs = syntax.multiline_comment_start.as_bytes();
+    let mce = syntax.multiline_comment_end.as_bytes();
+    let strings = syntax.flags & HighlightFlag::String as u8 == HighlightFlag::String as u8;
+
+    // Only a few bytes can change the state, the rest are skipped quickly.
+    let delimiters = [scs.first(), mcs.first(), mce.first()];
+    let is_special =
+        |c: &u8| *c == b'"' || *c == b'\'' || *c == b'\\' || delimiters.contains(&Some(c));
+
+    let bytes = text.as_bytes();
+    let mut in_string = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        if !is_special(&bytes[i]) {
+            i += 1;
+            continue;
+        }
+        let slice = &bytes[i..];
+        let c = bytes[i];
 
-fn editor_update_syntax(edit_syntax: Option<&EditorSyntax>, rows: &mut [Row], cy: usize) {
-    let rows = std::cell::RefCell::new(rows);
-    let numrows = rows.borrow().len();
-    let mut brows = rows.borrow_mut();
-    let (left, right) = brows.split_at_mut(cy);
-    let row = &mut right[0];
-    let prev_row = left.last();
-
-    if let Some(syntax) = edit_syntax {
-        let mut in_comment = row.idx > 0 && prev_row.map(|r| r.hl_open_comment).unwrap_or(false);
-        apply_syntax(syntax, in_comment, row);
-        let mut changed = row.hl_open_comment != in_comment;
-        row.hl_open_comment = in_comment;
-        while changed && row.idx + 1 < numrows {
-            let mut brows = rows.borrow_mut();
-            let (left, right) = brows.split_at_mut(row.idx + 1);
-            let row = &mut right[0];
-            let prev_row = left.last();
-            in_comment = row.idx > 0 && prev_row.map(|r| r.hl_open_comment).unwrap_or(false);
-            row.hl = vec![Highlight::Normal; row.render.len()];
-            apply_syntax(syntax, in_comment, row);
-            changed = row.hl_open_comment != in_comment;
-            row.hl_open_comment = in_comment;
-        }
-    }
-}
-
-fn apply_syntax(syntax: &EditorSyntax, mut in_comment: bool, row: &mut Row) {
+        if !scs.is_empty() && !in_string && !in_comment && slice.starts_with(scs) {
+            break;
+        }
+
+        if !mcs.is_empty() && !mce.is_empty() && !in_string {
+            if in_comment {
+                if slice.starts_with(mce) {
+                    i += mce.len();
+                    in_comment = false;
+                } else {
+                    i += 1;
+                }
+                continue;
+            } else if slice.starts_with(mcs) {
+                i += mcs.len();
+                in_comment = true;
+                continue;
+            }
+        }
+
+        if strings {
+            if in_string && c == b'\\' && i + 1 < bytes.len() {
+                i += 2;
+                continue;
+            }
+            if c == b'"' || c == b'\'' {
+                in_string = !in_string;
+            }
+        }
+        i += 1;
+    }
+
+    in_comment
+}
+
+/// Highlight `row`, returns whether it ends inside a multi-line comment.
+fn apply_syntax(syntax: &EditorSyntax, mut in_comment: bool, row: &mut Row) -> bool {
     let n = row.render.len();
     let mut prev_sep = true;
     let mut in_string = false;
@@ -339,7 +596,7 @@ fn apply_syntax(syntax: &EditorSyntax, mut in_comment: bool, row: &mut Row) {
 
     let row_render_slice = row.render.as_bytes();
     let mut i = 0;
-    while i < n {
+    'render: while i < n {
         let slice = &row_render_slice[i..];
         let c = row_render_slice[i] as char;
         let prev_hl = if i > 0 {
@@ -348,20 +605,18 @@ fn apply_syntax(syntax: &EditorSyntax, mut in_comment: bool, row: &mut Row) {
             Highlight::Normal
         };
 
-        if scs_len > 0 && !in_string && !in_comment {
-            if slice.starts_with(scs.as_bytes()) {
-                let slice = &mut row.hl[i..];
-                for el in slice {
-                    *el = Highlight::Comment;
-                }
-                break;
+        if scs_len > 0 && !in_string && !in_comment && slice.starts_with(scs.as_bytes()) {
+            let slice = &mut row.hl[i..];
+            for el in slice {
+                *el = Highlight::Comment;
             }
+            break;
         }
 
         if mcs_len > 0 && mce_len > 0 && !in_string {
             if in_comment {
                 row.hl[i] = Highlight::MLComment;
-                if row.render.starts_with(mce) {
+                if slice.starts_with(mce.as_bytes()) {
                     let slice = &mut row.hl[i..i + mce_len];
                     for el in slice {
                         *el = Highlight::MLComment;
@@ -409,15 +664,14 @@ fn apply_syntax(syntax: &EditorSyntax, mut in_comment: bool, row: &mut Row) {
             }
         }
 
-        if flags & HighlightFlag::Number as u8 == HighlightFlag::Number as u8 {
-            if c.is_ascii_digit() && (prev_sep || prev_hl == Highlight::Number)
-                || (c == '.' && prev_hl == Highlight::Number)
-            {
-                row.hl[i] = Highlight::Number;
-                i += 1;
-                prev_sep = false;
-                continue;
-            }
+        if flags & HighlightFlag::Number as u8 == HighlightFlag::Number as u8
+            && (c.is_ascii_digit() && (prev_sep || prev_hl == Highlight::Number)
+                || (c == '.' && prev_hl == Highlight::Number))
+        {
+            row.hl[i] = Highlight::Number;
+            i += 1;
+            prev_sep = false;
+            continue;
         }
 
         if prev_sep {
@@ -432,10 +686,11 @@ fn apply_syntax(syntax: &EditorSyntax, mut in_comment: bool, row: &mut Row) {
                     is_kw2 = true;
                 }
 
-                let slice = &row.render[i..];
-                let bytes = slice.as_bytes();
+                let bytes = &row_render_slice[i..];
                 assert_eq!(kw.len(), klen);
-                if slice.starts_with(kw) && slice.len() > klen && is_seperator(bytes[klen] as char)
+                if bytes.starts_with(kw.as_bytes())
+                    && bytes.len() > klen
+                    && is_seperator(bytes[klen] as char)
                 {
                     let slice = &mut row.hl[i..i + klen];
                     for el in slice {
@@ -446,7 +701,8 @@ fn apply_syntax(syntax: &EditorSyntax, mut in_comment: bool, row: &mut Row) {
                         };
                     }
                     i += klen;
-                    break;
+                    prev_sep = false;
+                    continue 'render;
                 }
             }
         }
@@ -454,6 +710,19 @@ fn apply_syntax(syntax: &EditorSyntax, mut in_comment: bool, row: &mut Row) {
         prev_sep = is_seperator(c);
         i += 1;
     }
+
+    editor_highlight_controls(row);
+    in_comment
+}
+
+/// Mark the caret notation of control characters so it stands out from
+/// literal text.
+fn editor_highlight_controls(row: &mut Row) {
+    for range in row.controls.iter() {
+        for el in &mut row.hl[range.clone()] {
+            *el = Highlight::Control;
+        }
+    }
 }
 
 /// Check if a character is a seperator character
@@ -463,6 +732,7 @@ fn is_seperator(c: char) -> bool {
 
 fn editor_select_syntax_highlight(cfg: &mut EditorConfig) {
     cfg.editor_syntax = None;
+    editor_invalidate_rows(cfg, 0);
     if cfg.filename.is_none() {
         return;
     }
@@ -470,15 +740,8 @@ fn editor_select_syntax_highlight(cfg: &mut EditorConfig) {
     let ext = Path::new(cfg.filename.as_ref().unwrap()).extension();
     if let Some(ext) = ext {
         for syntax in cfg.hldb.iter() {
-            if syntax.filematch.contains(ext.to_str().unwrap()) {
+            if syntax.filematch.contains(ext.to_string_lossy().as_ref()) {
                 cfg.editor_syntax = Some(syntax.clone());
-                for _ in 0..cfg.numrows {
-                    editor_update_syntax(
-                        cfg.editor_syntax.as_ref(),
-                        cfg.rows.as_mut_slice(),
-                        cfg.cy,
-                    );
-                }
                 return;
             }
         }
@@ -487,163 +750,235 @@ fn editor_select_syntax_highlight(cfg: &mut EditorConfig) {
 
 // *** Row Operations ***
 
-fn editor_row_cx_to_rx(row: &Row, cx: usize) -> usize {
+/// Number of terminal columns a grapheme cluster takes up at column `rx`.
+fn grapheme_width(g: &str, rx: usize) -> usize {
+    if g == "\t" {
+        KILO_TAB_STOP - (rx % KILO_TAB_STOP)
+    } else if g.chars().any(char::is_control) {
+        g.chars().map(|c| control_repr(c).width()).sum()
+    } else {
+        g.width()
+    }
+}
+
+/// Printable stand-in for a character that must not reach the terminal
+/// as is: `^A` for C0 controls, `^?` for DEL and `<9b>` for C1 controls.
+fn control_repr(c: char) -> String {
+    match c as u32 {
+        0x00..=0x1f | 0x7f => format!("^{}", (c as u8 ^ 0x40) as char),
+        0x80..=0x9f => format!("<{:02x}>", c as u32),
+        _ => c.to_string(),
+    }
+}
+
+/// Replace control characters in text shown outside the text area.
+fn sanitize(s: &str) -> String {
+    s.chars().map(control_repr).collect()
+}
+
+/// Display width of already rendered text (no tabs left to expand).
+fn render_width(s: &str) -> usize {
+    s.graphemes(true).map(|g| g.width()).sum()
+}
+
+/// Byte offset of the grapheme cluster before `at`.
+fn prev_grapheme(s: &str, at: usize) -> usize {
+    s[..at]
+        .grapheme_indices(true)
+        .next_back()
+        .map_or(0, |(i, _)| i)
+}
+
+/// Byte offset just past the grapheme cluster starting at `at`.
+fn next_grapheme(s: &str, at: usize) -> usize {
+    s[at..]
+        .graphemes(true)
+        .next()
+        .map_or(s.len(), |g| at + g.len())
+}
+
+/// Convert the byte offset `cx` into `chars` to a screen column.
+fn editor_row_cx_to_rx(chars: &str, cx: usize) -> usize {
     let mut rx = 0;
-    let slice = &row.chars[..cx];
-    for c in slice.chars() {
-        if c == '\t' {
-            rx += (KILO_TAB_STOP - 1) - (rx % KILO_TAB_STOP);
-        }
-        rx += 1;
+    for g in chars[..cx].graphemes(true) {
+        rx += grapheme_width(g, rx);
     }
 
     rx
 }
 
-fn editor_row_rx_to_cx(row: &Row, rx: usize) -> usize {
+/// Convert a screen column to the byte offset of the grapheme cluster
+/// covering it.
+fn editor_row_rx_to_cx(chars: &str, rx: usize) -> usize {
     let mut cur_rx = 0;
-    let n = row.chars.len();
-    let slice = &row.chars[..n];
-    for (cx, c) in slice.chars().enumerate() {
-        if c == '\t' {
-            cur_rx += (KILO_TAB_STOP - 1) - (cur_rx % KILO_TAB_STOP);
-        }
-        cur_rx += 1;
+    for (cx, g) in chars.grapheme_indices(true) {
+        cur_rx += grapheme_width(g, cur_rx);
 
         if cur_rx > rx {
             return cx;
         }
     }
 
-    n
+    chars.len()
 }
 
-fn editor_insert_row(cfg: &mut EditorConfig, chars: String, at: usize) {
-    if at > cfg.numrows {
-        return;
-    }
+/// Drop what is cached about line `at` and below after they changed.
+fn editor_invalidate_rows(cfg: &mut EditorConfig, at: usize) {
+    cfg.rows.retain(|&y, _| y < at);
+    cfg.open_comments.truncate(at);
+}
+
+/// Insert `text` at a position in the buffer, recording it for undo.
+fn editor_buffer_insert(cfg: &mut EditorConfig, at: (usize, usize), text: &str) {
+    cfg.buffer.insert(at, text);
+    cfg.history.record(Edit::Insert(at, text.to_string()));
+    editor_invalidate_rows(cfg, at.0);
+}
+
+/// Remove the text between two positions in the buffer, recording it for
+/// undo.
+fn editor_buffer_remove(cfg: &mut EditorConfig, start: (usize, usize), end: (usize, usize)) {
+    let text = cfg.buffer.slice(start, end);
+    cfg.buffer.remove(start, end);
+    cfg.history.record(Edit::Remove(start, text));
+    editor_invalidate_rows(cfg, start.0);
+}
 
-    for j in at + 1..cfg.numrows {
-        cfg.rows[j].idx += 1;
+fn editor_insert_row(cfg: &mut EditorConfig, chars: &str, at: usize) {
+    if at > cfg.buffer.len() {
+        return;
     }
 
-    let mut row = Row::default();
-    row.idx = at;
-    row.hl = vec![Highlight::Normal; row.render.len()];
-    row.chars = chars;
-    row.hl_open_comment = false;
-    cfg.rows.insert(at, row);
-    cfg.numrows = cfg.rows.len();
-    cfg.dirty = true;
-    editor_update_row(cfg.editor_syntax.as_ref(), cfg.rows.as_mut_slice(), at);
+    editor_buffer_insert(cfg, (at, 0), &format!("{}\n", chars));
+    editor_set_dirty(cfg);
 }
 
-fn editor_update_row(syntax: Option<&EditorSyntax>, rows: &mut [Row], cy: usize) {
-    let mut idx = 0;
-    let row = &mut rows[cy];
-    row.render.clear();
+/// Lay out `chars` for the screen, without syntax highlighting.
+fn editor_render_row(chars: String) -> Row {
+    let mut row = Row {
+        chars,
+        ..Row::default()
+    };
+    let mut rx = 0;
+
+    for g in row.chars.graphemes(true) {
+        let width = grapheme_width(g, rx);
+        if g == "\t" {
+            row.render.push_str(&" ".repeat(width));
+        } else if g.chars().any(char::is_control) {
+            let start = row.render.len();
+            row.render.push_str(&sanitize(g));
+            row.controls.push(start..row.render.len());
+        } else {
+            row.render.push_str(g);
+        }
+        rx += width;
+    }
+    row.hl = vec![Highlight::Normal; row.render.len()];
+    editor_highlight_controls(&mut row);
 
-    for c in row.chars.chars() {
-        if c == '\t' {
-            row.render.push(' ');
-            idx += 1;
+    row
+}
 
-            while idx % KILO_TAB_STOP != 0 {
-                row.render.push(' ');
-                idx += 1;
+/// Rendered and highlighted line `y`, from the cache if it is there.
+fn editor_row(cfg: &mut EditorConfig, y: usize) -> &mut Row {
+    if !cfg.rows.contains_key(&y) {
+        let in_comment = editor_in_comment(cfg, y);
+        let mut row = editor_render_row(cfg.buffer.line(y));
+        if let Some(syntax) = cfg.editor_syntax.as_ref() {
+            row.hl_open_comment = apply_syntax(syntax, in_comment, &mut row);
+            if cfg.open_comments.len() == y {
+                cfg.open_comments.push(row.hl_open_comment);
             }
-        } else {
-            row.render.push(c);
         }
+        cfg.rows.insert(y, row);
     }
 
-    editor_update_syntax(syntax, rows, cy);
+    cfg.rows.get_mut(&y).unwrap()
 }
 
-fn editor_free_row(row: &mut Row) {
-    row.chars.clear();
-    row.render.clear();
-    row.hl.clear();
+/// Render the lines coming into view and forget about the others.
+fn editor_update_rows(cfg: &mut EditorConfig) {
+    cfg.numrows = cfg.buffer.len();
+    let visible = cfg.rowoff..(cfg.rowoff + cfg.screenrows).min(cfg.numrows);
+    cfg.rows.retain(|y, _| visible.contains(y));
+    for y in visible {
+        editor_row(cfg, y);
+    }
 }
 
 fn editor_del_row(cfg: &mut EditorConfig, at: usize) {
-    if at >= cfg.numrows {
+    if at >= cfg.buffer.len() {
         return;
     }
-    editor_free_row(&mut cfg.rows[cfg.cy]);
-    cfg.rows.remove(at);
-    cfg.numrows -= 1;
-    for j in at..cfg.numrows - 1 {
-        cfg.rows[j].idx = cfg.rows[j].idx.saturating_sub(1);
-    }
-    cfg.dirty = true;
+
+    editor_buffer_remove(cfg, (at, 0), (at + 1, 0));
+    editor_set_dirty(cfg);
 }
 
-fn editor_row_insert_char(
-    syntax: Option<&EditorSyntax>,
-    rows: &mut [Row],
-    mut at: usize,
-    c: char,
-    cy: usize,
-) {
-    let row = &mut rows[cy];
-    if at > row.chars.len() {
-        at = row.chars.len();
-    }
-    row.chars.insert(at, c);
-    editor_update_row(syntax, rows, cy);
+fn editor_row_insert_char(cfg: &mut EditorConfig, mut at: usize, c: char, cy: usize) {
+    at = at.min(cfg.buffer.line_len(cy));
+    editor_buffer_insert(cfg, (cy, at), c.encode_utf8(&mut [0; 4]));
 }
 
-fn editor_row_del_char(syntax: Option<&EditorSyntax>, rows: &mut [Row], at: usize, cy: usize) {
-    let row = &mut rows[cy];
-    if at >= row.chars.len() {
+/// Remove the grapheme cluster starting at byte offset `at`.
+fn editor_row_del_char(cfg: &mut EditorConfig, at: usize, cy: usize) {
+    let chars = cfg.buffer.line(cy);
+    if at >= chars.len() {
         return;
     }
-    row.chars.remove(at);
-    editor_update_row(syntax, rows, cy);
-}
-
-fn editor_row_append_str(syntax: Option<&EditorSyntax>, rows: &mut [Row], cy: usize, s: &str) {
-    let row = &mut rows[cy];
-    row.chars.push_str(s);
-    editor_update_row(syntax, rows, cy);
+    let end = next_grapheme(&chars, at);
+    editor_buffer_remove(cfg, (cy, at), (cy, end));
 }
 
 // *** Editor operations ***
 
 fn editor_insert_char(cfg: &mut EditorConfig, c: char) {
-    if cfg.cy == cfg.numrows {
-        editor_insert_row(cfg, String::new(), 0);
-    }
-    editor_row_insert_char(
-        cfg.editor_syntax.as_ref(),
-        cfg.rows.as_mut_slice(),
-        cfg.cx,
-        c,
-        cfg.cy,
-    );
+    if cfg.cy == cfg.buffer.len() {
+        editor_insert_row(cfg, "", cfg.buffer.len());
+    }
+    editor_row_insert_char(cfg, cfg.cx, c, cfg.cy);
 
-    cfg.cx += 1;
-    cfg.dirty = true;
+    cfg.cx += c.len_utf8();
+    editor_set_dirty(cfg);
+}
+
+/// Insert a block of text at the cursor in one go.
+///
+/// Used for pastes, so the whole text goes into the buffer in one edit
+/// and every affected row is rendered a single time.
+fn editor_insert_text(cfg: &mut EditorConfig, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    if cfg.cy == cfg.buffer.len() {
+        editor_insert_row(cfg, "", cfg.buffer.len());
+    }
+
+    editor_buffer_insert(cfg, (cfg.cy, cfg.cx), text);
+    match text.rfind('\n') {
+        Some(i) => {
+            cfg.cy += text.matches('\n').count();
+            cfg.cx = text.len() - i - 1;
+        }
+        None => cfg.cx += text.len(),
+    }
+    editor_set_dirty(cfg);
 }
 
 fn editor_insert_new_line(cfg: &mut EditorConfig) {
-    if cfg.cx == 0 {
-        editor_insert_row(cfg, String::new(), 0);
+    if cfg.cy == cfg.buffer.len() {
+        editor_insert_row(cfg, "", cfg.cy);
     } else {
-        let chars = cfg.rows[cfg.cy].chars.to_string();
-        editor_insert_row(cfg, String::from(&chars[cfg.cx - 1..]), cfg.cy + 1);
-
-        let row = &mut cfg.rows[cfg.cy];
-        row.chars = String::from(&chars[..cfg.cx - 1]);
-        editor_update_row(cfg.editor_syntax.as_ref(), cfg.rows.as_mut_slice(), cfg.cy);
+        editor_buffer_insert(cfg, (cfg.cy, cfg.cx), "\n");
+        editor_set_dirty(cfg);
     }
     cfg.cy += 1;
     cfg.cx = 0;
 }
 
 fn editor_del_char(cfg: &mut EditorConfig) {
-    if cfg.cy == cfg.numrows {
+    if cfg.cy == cfg.buffer.len() {
         return;
     }
     if cfg.cx == 0 && cfg.cy == 0 {
@@ -651,36 +986,272 @@ fn editor_del_char(cfg: &mut EditorConfig) {
     }
 
     if cfg.cx > 0 {
-        editor_row_del_char(
-            cfg.editor_syntax.as_ref(),
-            cfg.rows.as_mut_slice(),
-            cfg.cx - 1,
-            cfg.cy,
-        );
-        cfg.cx -= 1;
+        let at = prev_grapheme(&cfg.buffer.line(cfg.cy), cfg.cx);
+        editor_row_del_char(cfg, at, cfg.cy);
+        cfg.cx = at;
     } else {
-        let chars = &cfg.rows[cfg.cy].chars.to_string();
-        cfg.cx = cfg.rows[cfg.cy - 1].chars.len();
-        editor_row_append_str(
-            cfg.editor_syntax.as_ref(),
-            cfg.rows.as_mut_slice(),
-            cfg.cy - 1,
-            chars,
-        );
-        editor_del_row(cfg, cfg.cy);
+        // Join the line with the one above.
+        cfg.cx = cfg.buffer.line_len(cfg.cy - 1);
+        editor_buffer_remove(cfg, (cfg.cy - 1, cfg.cx), (cfg.cy, 0));
         cfg.cy -= 1;
     }
-    cfg.dirty = true;
+    editor_set_dirty(cfg);
+}
+
+/// Ordered `(start, end)` positions of the current selection, if any.
+fn editor_selection(cfg: &EditorConfig) -> Option<((usize, usize), (usize, usize))> {
+    let anchor = cfg.selection_anchor?;
+    let cursor = (cfg.cy, cfg.cx);
+    if anchor <= cursor {
+        Some((anchor, cursor))
+    } else {
+        Some((cursor, anchor))
+    }
+}
+
+/// Clamp a position past the last row to the end of the buffer.
+fn editor_clamp_pos(cfg: &EditorConfig, (y, x): (usize, usize)) -> (usize, usize) {
+    let numrows = cfg.buffer.len();
+    if y < numrows {
+        (y, x)
+    } else if numrows > 0 {
+        (numrows - 1, cfg.buffer.line_len(numrows - 1))
+    } else {
+        (0, 0)
+    }
+}
+
+/// Text between two buffer positions, rows joined with `\n`.
+fn editor_text_range(cfg: &EditorConfig, start: (usize, usize), end: (usize, usize)) -> String {
+    let ((sy, sx), (ey, ex)) = (editor_clamp_pos(cfg, start), editor_clamp_pos(cfg, end));
+    if cfg.buffer.len() == 0 {
+        return String::new();
+    }
+
+    cfg.buffer.slice((sy, sx), (ey, ex))
+}
+
+/// Delete the text between two buffer positions, leaving the cursor there.
+fn editor_delete_range(cfg: &mut EditorConfig, start: (usize, usize), end: (usize, usize)) {
+    let ((sy, sx), (ey, ex)) = (editor_clamp_pos(cfg, start), editor_clamp_pos(cfg, end));
+    if cfg.buffer.len() == 0 || (sy, sx) == (ey, ex) {
+        return;
+    }
+
+    editor_buffer_remove(cfg, (sy, sx), (ey, ex));
+    cfg.cy = sy;
+    cfg.cx = sx;
+    editor_set_dirty(cfg);
+}
+
+/// Run a command editing the buffer, so it is undone as a whole or as
+/// part of its `group`.
+fn editor_edit<F>(cfg: &mut EditorConfig, group: Group, edit: F)
+where
+    F: FnOnce(&mut EditorConfig),
+{
+    cfg.history.begin(group, (cfg.cy, cfg.cx));
+    edit(cfg);
+    cfg.history.end((cfg.cy, cfg.cx));
+}
+
+/// Take the buffer elsewhere in its history with `step`, such as
+/// `History::undo`, or tell the user `msg` when there is nowhere to go.
+fn editor_history_step<F>(cfg: &mut EditorConfig, step: F, msg: &str) -> bool
+where
+    F: FnOnce(&mut History, &mut Buffer) -> Option<(usize, (usize, usize))>,
+{
+    match step(&mut cfg.history, &mut cfg.buffer) {
+        Some(moved) => {
+            editor_history_moved(cfg, moved);
+            true
+        }
+        None => {
+            editor_set_status_msg(cfg, msg.to_string());
+            false
+        }
+    }
+}
+
+/// Go back to the state the buffer was in some time ago, or when it was
+/// last saved.
+fn editor_time_travel(cfg: &mut EditorConfig) {
+    let query = match editor_prompt(
+        cfg,
+        |buf| format!("Go back to: {} (e.g. 10m, 1h, save; ESC to cancel)", buf),
+        None::<fn(&mut EditorConfig, &str, EditorKey)>,
+    ) {
+        Some(query) => query,
+        None => return,
+    };
+
+    let id = if query.trim() == "save" {
+        match cfg.history.saved() {
+            Some(id) => id,
+            None => {
+                editor_set_status_msg(cfg, "No state here was ever saved".to_string());
+                return;
+            }
+        }
+    } else {
+        match parse_time_ago(&query) {
+            Some(ago) => cfg
+                .history
+                .state_at(SystemTime::now().checked_sub(ago).unwrap_or(UNIX_EPOCH)),
+            None => {
+                editor_set_status_msg(cfg, format!("Can't make sense of {}", query));
+                return;
+            }
+        }
+    };
+    if editor_history_step(
+        cfg,
+        |history, buffer| history.goto(id, buffer),
+        "Already there",
+    ) {
+        let time = clock_time(cfg.history.time(id));
+        editor_set_status_msg(cfg, format!("Back to the state of {}", time));
+    }
+}
+
+/// Commands moving through the undo history, bound to Alt and a key.
+fn editor_history_command(cfg: &mut EditorConfig, c: char) {
+    match c {
+        '-' => {
+            let msg = "Already at the oldest change";
+            editor_history_step(cfg, |history, buffer| history.step(false, buffer), msg);
+        }
+        '=' => {
+            let msg = "Already at the newest change";
+            editor_history_step(cfg, |history, buffer| history.step(true, buffer), msg);
+        }
+        ',' | '.' => {
+            let forward = c == '.';
+            let step =
+                |history: &mut History, buffer: &mut Buffer| history.switch_branch(forward, buffer);
+            editor_history_step(cfg, step, "No other branch");
+        }
+        't' => editor_time_travel(cfg),
+        'h' => cfg.history_panel = !cfg.history_panel,
+        _ => (),
+    }
+}
+
+/// How long ago `text` means, like `90s`, `10m`, `2h ago` or `1d`.
+fn parse_time_ago(text: &str) -> Option<Duration> {
+    let text = text.trim().trim_end_matches("ago").trim_end();
+    let (n, unit) = text.split_at(text.find(|c: char| !c.is_ascii_digit())?);
+    let secs = match unit.trim_start() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    n.parse::<u64>()
+        .ok()?
+        .checked_mul(secs)
+        .map(Duration::from_secs)
+}
+
+/// Catch up after the buffer was taken back or forward in its history,
+/// from line `y` on, and put the cursor where the change was made.
+fn editor_history_moved(cfg: &mut EditorConfig, (y, (cy, cx)): (usize, (usize, usize))) {
+    editor_invalidate_rows(cfg, y);
+    cfg.cy = cy;
+    cfg.cx = cx;
+    if cfg.history.is_saved() {
+        cfg.dirty = false;
+        editor_cancel_timer(cfg, Timer::Swap);
+        editor_remove_swap(cfg);
+    } else {
+        editor_set_dirty(cfg);
+    }
+}
+
+// *** Clipboard ***
+
+/// Put `text` in the register and, size permitting, on the host clipboard.
+fn editor_clipboard_set(cfg: &mut EditorConfig, text: String, what: &str) {
+    let msg = match clipboard::osc52_copy(&text, cfg.clipboard_max) {
+        Some(seq) => {
+            let _ = cfg.terminal.write_frame(seq.as_bytes());
+            format!("{} {} bytes", what, text.len())
+        }
+        None => format!(
+            "{} {} bytes, too big for the terminal clipboard (KILO_CLIPBOARD_MAX)",
+            what,
+            text.len()
+        ),
+    };
+    cfg.clipboard = text;
+    editor_set_status_msg(cfg, msg);
+}
+
+/// Contents of the host clipboard if reading it back is enabled and the
+/// terminal answers, otherwise of the register.
+fn editor_clipboard_get(cfg: &mut EditorConfig) -> String {
+    if cfg.clipboard_read && cfg.terminal.write_frame(OSC52_QUERY.as_bytes()).is_ok() {
+        let mut reply = Vec::new();
+        let mut timeouts = 0;
+        while timeouts < 3 && reply.len() < cfg.clipboard_max + 16 {
+            match cfg.terminal.read_byte() {
+                Ok(Some(0x07)) => break,
+                Ok(Some(b'\\')) if reply.last() == Some(&0x1b) => break,
+                Ok(Some(c)) => reply.push(c),
+                Ok(None) => timeouts += 1,
+                Err(_) => break,
+            }
+        }
+        if let Some(text) = clipboard::parse_osc52_reply(&reply) {
+            return text;
+        }
+    }
+
+    cfg.clipboard.clone()
+}
+
+/// Copy the selection, or the current line when nothing is selected.
+fn editor_copy(cfg: &mut EditorConfig) {
+    if let Some((start, end)) = editor_selection(cfg) {
+        let text = editor_text_range(cfg, start, end);
+        editor_clipboard_set(cfg, text, "Copied");
+    } else if cfg.cy < cfg.buffer.len() {
+        let text = format!("{}\n", cfg.buffer.line(cfg.cy));
+        editor_clipboard_set(cfg, text, "Copied line,");
+    }
+}
+
+/// Cut the selection, or the current line when nothing is selected.
+fn editor_cut(cfg: &mut EditorConfig) {
+    if let Some((start, end)) = editor_selection(cfg) {
+        let text = editor_text_range(cfg, start, end);
+        editor_delete_range(cfg, start, end);
+        cfg.selection_anchor = None;
+        editor_clipboard_set(cfg, text, "Cut");
+    } else if cfg.cy < cfg.buffer.len() {
+        let text = format!("{}\n", cfg.buffer.line(cfg.cy));
+        editor_del_row(cfg, cfg.cy);
+        cfg.cx = 0;
+        editor_clipboard_set(cfg, text, "Cut line,");
+    }
+}
+
+fn editor_paste(cfg: &mut EditorConfig) {
+    let text = editor_clipboard_get(cfg);
+    editor_insert_text(cfg, &text);
 }
 
 // *** Find ***
 
 fn editor_find_callback(cfg: &mut EditorConfig, query: &str, key: EditorKey) {
-    if let Some(ref saved_hl) = cfg.saved_hl {
-        let row = &mut cfg.rows[cfg.saved_hl_line as usize];
-        let original_hl = &mut row.hl;
-        for (i, el) in original_hl.iter_mut().enumerate() {
-            *el = saved_hl[i];
+    if let Some(saved_hl) = cfg.saved_hl.take() {
+        // A row that left the cache or was rendered anew since is
+        // highlighted afresh anyway.
+        if let Some(row) = cfg.rows.get_mut(&(cfg.saved_hl_line as usize)) {
+            if row.hl.len() == saved_hl.len() {
+                row.hl = saved_hl;
+            }
         }
     }
     match key {
@@ -705,42 +1276,45 @@ fn editor_find_callback(cfg: &mut EditorConfig, query: &str, key: EditorKey) {
         cfg.direction = Direction::Forward;
     }
 
-    let mut current = cfg.last_match;
-    for _ in 0..cfg.numrows {
-        match cfg.direction {
-            Direction::Forward => {
-                current += 1;
-            }
-            Direction::Backward => {
-                current -= 1;
-            }
-        }
-        if current == -1 {
-            current = (cfg.numrows - 1) as isize;
-        } else if current == cfg.numrows as isize {
-            current = 0;
+    let numrows = cfg.buffer.len();
+    if numrows == 0 {
+        return;
+    }
+    let (start, forward) = match cfg.direction {
+        Direction::Forward => ((cfg.last_match + 1) as usize % numrows, true),
+        Direction::Backward => ((cfg.last_match as usize + numrows - 1) % numrows, false),
+    };
+
+    let current = match cfg.buffer.find_line(query, start, forward) {
+        Some(current) => current,
+        None => return,
+    };
+    cfg.last_match = current as isize;
+    cfg.cy = current;
+    cfg.cx = 0;
+    cfg.rowoff = numrows;
+
+    // A match past where a long line is cut off, or through bytes that
+    // aren't UTF-8, has no column to show.
+    let chars = cfg.buffer.line(current);
+    if let Some(cx) = chars.find(query) {
+        cfg.cx = cx;
+
+        // The query has no tabs or control characters, it is rendered as
+        // is; only the text before it may come out longer.
+        let index = editor_render_row(chars[..cx].to_string()).render.len();
+        cfg.saved_hl_line = current as isize;
+        let row = editor_row(cfg, current);
+        let saved_hl = row.hl.clone();
+
+        let end = (index + query.len()).min(row.hl.len());
+        let slice = &mut row.hl[index.min(end)..end];
+        for el in slice {
+            *el = Highlight::Match;
         }
-
-        let row = &mut cfg.rows[current as usize];
-        let match_index = row.render.find(query);
-        if let Some(index) = match_index {
-            cfg.last_match = current;
-            cfg.cy = current as usize;
-            cfg.cx = editor_row_rx_to_cx(row, index);
-            cfg.rowoff = cfg.numrows;
-
-            cfg.saved_hl_line = current;
-            cfg.saved_hl = Some(row.hl.clone());
-
-            let slice = &mut row.hl[index..index + query.len()];
-            for el in slice {
-                *el = Highlight::Match;
-            }
-
-            break;
-        }
-    }
-}
+        cfg.saved_hl = Some(saved_hl);
+    }
+}
 
 fn editor_find(cfg: &mut EditorConfig) {
     let saved_cx = cfg.cx;
@@ -762,18 +1336,82 @@ fn editor_find(cfg: &mut EditorConfig) {
 
 // *** Output ***
 
-/// Clear screen and move cursor to top of the screen.
-fn term_refresh() {
-    let mut out = io::stdout();
-    let out = out.by_ref();
-    out.write(b"\x1b[2J").unwrap();
-    out.write(b"\x1b[H").unwrap();
+/// Switch to raw mode and the alternate screen.
+fn editor_init_terminal(cfg: &mut EditorConfig) -> Result<()> {
+    let terminal = &mut cfg.terminal;
+    terminal.enable_raw_mode().map_err(EditorError::Terminal)?;
+    terminal
+        .write_frame(ENTER_SCREEN)
+        .map_err(EditorError::Terminal)?;
+    if cfg.kitty_keyboard {
+        terminal
+            .write_frame(KITTY_KEYBOARD_PUSH)
+            .map_err(EditorError::Terminal)?;
+    }
+    // The title was popped off the stack when we last left.
+    cfg.title = None;
+    Ok(())
+}
+
+/// Leave the alternate screen and restore the original terminal settings.
+fn editor_restore_terminal(cfg: &mut EditorConfig) -> Result<()> {
+    let terminal = &mut cfg.terminal;
+    if cfg.kitty_keyboard {
+        terminal
+            .write_frame(KITTY_KEYBOARD_POP)
+            .map_err(EditorError::Terminal)?;
+    }
+    terminal
+        .write_frame(LEAVE_SCREEN)
+        .map_err(EditorError::Terminal)?;
+    terminal.disable_raw_mode().map_err(EditorError::Terminal)
+}
+
+/// Give the terminal back and exit with `err` when the editor can't go on.
+fn editor_die(cfg: &mut EditorConfig, err: EditorError) -> ! {
+    let _ = editor_restore_terminal(cfg);
+    eprintln!("kilo: {}", err);
+    exit(1);
+}
+
+/// Turn on the kitty keyboard protocol if the terminal supports it.
+///
+/// Terminals that know the protocol answer the `CSI ? u` query with their
+/// current flags. Every terminal answers the device attributes request that
+/// follows it, which tells us when to stop waiting.
+fn editor_enable_kitty_keyboard(cfg: &mut EditorConfig) -> Result<()> {
+    let terminal = &mut cfg.terminal;
+    terminal
+        .write_frame(KITTY_KEYBOARD_QUERY)
+        .map_err(EditorError::Terminal)?;
+
+    let mut reply = Vec::new();
+    let mut timeouts = 0;
+    while timeouts < 5 && reply.len() < 64 {
+        match terminal.read_byte().map_err(EditorError::Terminal)? {
+            Some(b'c') => break,
+            Some(c) => reply.push(c),
+            None => timeouts += 1,
+        }
+    }
+
+    let reply = String::from_utf8_lossy(&reply);
+    if reply
+        .split('\x1b')
+        .any(|seq| seq.starts_with("[?") && seq.ends_with('u'))
+    {
+        cfg.kitty_keyboard = true;
+        terminal
+            .write_frame(KITTY_KEYBOARD_PUSH)
+            .map_err(EditorError::Terminal)?;
+    }
+    Ok(())
 }
 
 fn editor_scroll(cfg: &mut EditorConfig) {
     cfg.rx = 0;
-    if cfg.cy < cfg.numrows {
-        cfg.rx = editor_row_cx_to_rx(&cfg.rows[cfg.cy], cfg.cx);
+    if cfg.cy < cfg.buffer.len() {
+        cfg.rx = editor_row_cx_to_rx(&cfg.buffer.line(cfg.cy), cfg.cx);
     }
 
     if cfg.cy < cfg.rowoff {
@@ -785,95 +1423,205 @@ fn editor_scroll(cfg: &mut EditorConfig) {
     if cfg.rx < cfg.coloff {
         cfg.coloff = cfg.rx;
     }
-    if cfg.rx >= cfg.coloff + cfg.screencols {
-        cfg.coloff = cfg.rx - cfg.screencols + 1;
+    let cols = editor_text_cols(cfg);
+    if cfg.rx >= cfg.coloff + cols {
+        cfg.coloff = cfg.rx - cols + 1;
+    }
+}
+
+/// Window title for the buffer, e.g. `* main.rs — kilo` when modified.
+fn editor_title(cfg: &EditorConfig) -> String {
+    let name = cfg
+        .filename
+        .as_deref()
+        .map(|filename| {
+            let path = Path::new(filename);
+            let name = path.file_name().unwrap_or(path.as_os_str());
+            sanitize(&name.to_string_lossy())
+        })
+        .unwrap_or_else(|| "[No Name]".to_string());
+
+    format!("{}{} — kilo", if cfg.dirty { "* " } else { "" }, name)
+}
+
+/// Re-query the window size after a SIGWINCH.
+///
+/// Clamps the cursor and offsets to the new geometry and asks for a
+/// full redraw on the next refresh.
+fn editor_handle_resize(cfg: &mut EditorConfig) {
+    if let Some((rows, cols)) = cfg.terminal.size() {
+        cfg.screenrows = rows.saturating_sub(2).max(1);
+        cfg.screencols = cols.max(1);
+    }
+
+    if cfg.cy > cfg.buffer.len() {
+        cfg.cy = cfg.buffer.len();
     }
+    cfg.cx = cfg.cx.min(cfg.buffer.line_len(cfg.cy));
+    editor_scroll(cfg);
+    cfg.full_redraw = true;
 }
 
-fn editor_draw_rows(cfg: &EditorConfig, abuf: &mut String) {
+/// Draw the text area, the rows on screen must be up to date as left by
+/// `editor_update_rows`.
+fn editor_draw_rows(cfg: &EditorConfig, frame: &mut Frame) {
+    let theme = &THEMES[cfg.theme];
+    let numrows = cfg.numrows;
+    let cols = editor_text_cols(cfg);
+
     for y in 0..cfg.screenrows {
         let filerow = y + cfg.rowoff;
-        if filerow >= cfg.numrows {
-            if cfg.numrows == 0 && y == cfg.screenrows / 3 {
+        if filerow >= numrows {
+            if numrows == 0 && y == cfg.screenrows / 3 {
                 let welcome = format!("Kilo editor -- version {}", env!("CARGO_PKG_VERSION"));
                 let mut welcomelen = welcome.len();
-                if welcomelen > cfg.screencols {
-                    welcomelen = cfg.screencols;
+                if welcomelen > cols {
+                    welcomelen = cols;
                 }
-                let mut padding = (cfg.screencols - welcomelen) / 2;
+                let padding = (cols - welcomelen) / 2;
 
                 if padding > 0 {
-                    abuf.push('~');
-                    padding -= 1;
+                    frame.print(y, 0, "~", theme.normal);
                 }
-
-                while padding > 0 {
-                    abuf.push(' ');
-                    padding -= 1;
-                }
-                abuf.push_str(&welcome);
+                frame.print(y, padding, &welcome[..welcomelen], theme.normal);
             } else {
-                abuf.push('~');
+                frame.print(y, 0, "~", theme.normal);
             }
         } else {
-            let row = &cfg.rows[filerow];
-
-            // since I am using usize, need to avoid overflow error
-            // when length of a row is less than coloff.
-            let mut len = row.render.len().saturating_sub(cfg.coloff);
-            if len > cfg.screencols {
-                len = cfg.screencols;
-            }
-
-            let slice = row.render.get(cfg.coloff..).unwrap();
+            let row = &cfg.rows[&filerow];
             let hl = &row.hl;
-            let mut curr_color: i32 = -1;
+            let selected = editor_row_selection(cfg, filerow);
+            let mut rx = 0;
 
-            for (i, c) in slice.chars().enumerate() {
-                if i == len {
+            for (i, g) in row.render.grapheme_indices(true) {
+                let width = g.width();
+                rx += width;
+                if rx <= cfg.coloff {
+                    continue;
+                }
+                if rx > cfg.coloff + cols {
                     break;
                 }
+                let rx = rx - width;
 
-                if !hl.is_empty() {
-                    if hl[i] == Highlight::Normal {
-                        if curr_color != -1 {
-                            abuf.push_str("\x1b[39m");
-                            curr_color = -1;
-                        }
-                    } else {
-                        let color: i32 = hl[i].into();
-                        if color != curr_color {
-                            curr_color = color;
-                            abuf.push_str(&format!("\x1b[{}m", color));
-                        }
-                    }
+                let is_selected = selected.is_some_and(|(start, end)| rx >= start && rx < end);
+                let style = if is_selected {
+                    theme.selection
+                } else {
+                    theme.style(hl.get(i).copied().unwrap_or(Highlight::Normal))
+                };
+                if rx < cfg.coloff {
+                    // Wide character cut in half by the left edge, the
+                    // rest of the cell stays blank.
+                    continue;
                 }
-                abuf.push(c);
+                frame.put(y, rx - cfg.coloff, g, width, style);
             }
-            abuf.push_str("\x1b[39m");
         }
+    }
+}
+
+/// Width of the text area, the history panel takes the right side when
+/// it is open and there is room.
+fn editor_text_cols(cfg: &EditorConfig) -> usize {
+    if cfg.history_panel && cfg.screencols >= 2 * KILO_HISTORY_PANEL_WIDTH {
+        cfg.screencols - KILO_HISTORY_PANEL_WIDTH
+    } else {
+        cfg.screencols
+    }
+}
+
+/// List the states in the undo history next to the text, with the time
+/// they were made, keeping the current one in view.
+///
+/// Branches are indented under the state they fork off from.
+fn editor_draw_history_panel(cfg: &EditorConfig, frame: &mut Frame) {
+    let theme = &THEMES[cfg.theme];
+    let x = editor_text_cols(cfg);
+    if x == cfg.screencols {
+        return;
+    }
 
-        abuf.push_str("\x1b[K");
-        abuf.push_str("\r\n");
+    let states = cfg.history.states();
+    let rows = cfg.screenrows.saturating_sub(1);
+    let current = states.iter().position(|state| state.current).unwrap_or(0);
+    let first = current
+        .saturating_sub(rows / 2)
+        .min(states.len().saturating_sub(rows));
+
+    for y in 0..cfg.screenrows {
+        frame.print(y, x, "│", theme.status_bar);
+    }
+    frame.print(0, x + 1, " Undo history", theme.status_bar);
+    for (y, state) in states.iter().skip(first).take(rows).enumerate() {
+        let line = format!(
+            " {}{} {}{}",
+            "  ".repeat(state.depth),
+            state.id,
+            clock_time(state.time),
+            if state.saved { " saved" } else { "" }
+        );
+        let style = if state.current {
+            theme.selection
+        } else {
+            theme.normal
+        };
+        frame.print(y + 1, x + 1, &line, style);
+    }
+}
+
+/// Local time of day, as `HH:MM:SS`.
+fn clock_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+/// Selected span of `filerow` as a `[start, end)` range of render columns.
+fn editor_row_selection(cfg: &EditorConfig, filerow: usize) -> Option<(usize, usize)> {
+    let ((sy, sx), (ey, ex)) = editor_selection(cfg)?;
+    if filerow < sy || filerow > ey {
+        return None;
     }
+
+    let row = cfg.rows.get(&filerow)?;
+    let start = if filerow == sy {
+        editor_row_cx_to_rx(&row.chars, sx)
+    } else {
+        0
+    };
+    let end = if filerow == ey {
+        editor_row_cx_to_rx(&row.chars, ex)
+    } else {
+        render_width(&row.render)
+    };
+    Some((start, end))
 }
 
 fn editor_set_status_msg(cfg: &mut EditorConfig, msg: String) {
     cfg.status_msg = msg;
-    cfg.status_msg_time = SystemTime::now();
+    editor_set_timer(cfg, Timer::StatusMessage, KILO_STATUS_MSG_TIMEOUT);
 }
 
-fn editor_draw_status_bar(cfg: &EditorConfig, abuf: &mut String) {
-    abuf.push_str("\x1b[7m");
+fn editor_draw_status_bar(cfg: &EditorConfig, frame: &mut Frame) {
+    let style = THEMES[cfg.theme].status_bar;
+    let y = cfg.screenrows;
     let mut status = format!(
-        "{0:.20} - {1} lines",
-        cfg.filename.as_ref().unwrap_or(&"[No Name]".to_string()),
-        cfg.numrows
+        "{0:.20} - {1}{2} lines",
+        cfg.filename
+            .as_deref()
+            .map_or("[No Name]".to_string(), sanitize),
+        cfg.numrows,
+        if cfg.buffer.is_complete() { "" } else { "+" }
     );
 
     if cfg.dirty {
         status.push_str("(modified)");
     }
+    if cfg.buffer.is_read_only() {
+        status.push_str("(read-only)");
+    }
 
     let rstatus = format!(
         "{} | {}/{}",
@@ -886,48 +1634,53 @@ fn editor_draw_status_bar(cfg: &EditorConfig, abuf: &mut String) {
     );
     let rlen = rstatus.len();
 
-    let mut len = status.len();
-    if len > cfg.screencols {
-        len = cfg.screencols;
-    }
-    abuf.push_str(&status);
-
-    for i in len..cfg.screencols {
-        if cfg.screencols - i == rlen {
-            abuf.push_str(&rstatus);
+    let mut len = frame.print(y, 0, &status, style);
+    while len < cfg.screencols {
+        if cfg.screencols - len == rlen {
+            frame.print(y, len, &rstatus, style);
             break;
         } else {
-            abuf.push_str(" ");
+            len = frame.print(y, len, " ", style);
         }
     }
-
-    abuf.push_str("\x1b[m");
-    abuf.push_str("\r\n");
 }
 
-fn editor_draw_message_bar(cfg: &EditorConfig, abuf: &mut String) {
-    abuf.push_str("\x1b[K");
-    let mut len = cfg.status_msg.len();
-    if len > cfg.screencols {
-        len = cfg.screencols;
-    }
-    if len > 0 && cfg.status_msg_time.elapsed().unwrap() < Duration::from_secs(5) {
-        abuf.push_str(&cfg.status_msg);
-    }
+fn editor_draw_message_bar(cfg: &EditorConfig, frame: &mut Frame) {
+    let y = cfg.screenrows + 1;
+    frame.print(y, 0, &sanitize(&cfg.status_msg), THEMES[cfg.theme].normal);
 }
 
-fn editor_refresh_screen(cfg: &mut EditorConfig) {
+fn editor_refresh_screen(cfg: &mut EditorConfig) -> Result<()> {
     editor_scroll(cfg);
+    editor_update_rows(cfg);
 
-    let mut out = io::stdout();
     let mut abuf = String::new();
+    let size = (cfg.screenrows + 2, cfg.screencols);
 
     abuf.push_str("\x1b[?25l");
-    abuf.push_str("\x1b[H");
+    let title = editor_title(cfg);
+    if cfg.title.as_ref() != Some(&title) {
+        abuf.push_str(&format!("\x1b]2;{}\x07", title));
+        cfg.title = Some(title);
+    }
+    if cfg.full_redraw || cfg.front.size() != size {
+        abuf.push_str("\x1b[2J");
+        cfg.front = Frame::new(size.0, size.1);
+        cfg.back = Frame::new(size.0, size.1);
+        cfg.full_redraw = false;
+    } else if cfg.rowoff != cfg.drawn_rowoff {
+        editor_scroll_region(cfg, &mut abuf);
+    }
+    cfg.drawn_rowoff = cfg.rowoff;
 
-    editor_draw_rows(cfg, &mut abuf);
-    editor_draw_status_bar(cfg, &mut abuf);
-    editor_draw_message_bar(cfg, &mut abuf);
+    let mut back = std::mem::replace(&mut cfg.back, Frame::new(0, 0));
+    back.clear(THEMES[cfg.theme].normal);
+    editor_draw_rows(cfg, &mut back);
+    editor_draw_history_panel(cfg, &mut back);
+    editor_draw_status_bar(cfg, &mut back);
+    editor_draw_message_bar(cfg, &mut back);
+    cfg.front.diff(&back, cfg.color_support, &mut abuf);
+    cfg.back = std::mem::replace(&mut cfg.front, back);
 
     abuf.push_str(&format!(
         "\x1b[{};{}H",
@@ -936,8 +1689,27 @@ fn editor_refresh_screen(cfg: &mut EditorConfig) {
     ));
     abuf.push_str("\x1b[?25h");
 
-    out.write(abuf.as_bytes()).unwrap();
-    out.flush().unwrap();
+    cfg.terminal
+        .write_frame(abuf.as_bytes())
+        .map_err(EditorError::Terminal)
+}
+
+/// Let the terminal scroll the text area when the view moved by less than
+/// a screenful, so only the lines scrolled in have to be drawn.
+fn editor_scroll_region(cfg: &mut EditorConfig, abuf: &mut String) {
+    let n = cfg.rowoff as isize - cfg.drawn_rowoff as isize;
+    if n.unsigned_abs() >= cfg.screenrows {
+        return;
+    }
+
+    abuf.push_str(&format!("\x1b[1;{}r", cfg.screenrows));
+    if n > 0 {
+        abuf.push_str(&format!("\x1b[{}S", n));
+    } else {
+        abuf.push_str(&format!("\x1b[{}T", -n));
+    }
+    abuf.push_str("\x1b[r");
+    cfg.front.scroll(0, cfg.screenrows, n);
 }
 
 // *** Input ***
@@ -954,10 +1726,17 @@ where
     let mut buf = String::new();
     loop {
         editor_set_status_msg(cfg, message(&buf));
-        editor_refresh_screen(cfg);
+        if let Err(e) = editor_refresh_screen(cfg) {
+            editor_die(cfg, e);
+        }
 
-        let key = editor_read_key();
+        let key = editor_read_key(cfg);
         match key {
+            EditorKey::Resize => {
+                editor_handle_resize(cfg);
+                continue;
+            }
+            EditorKey::Timer | EditorKey::Unknown => continue,
             EditorKey::EscapeSeq => {
                 editor_set_status_msg(cfg, String::new());
                 if let Some(cb) = callback.as_ref() {
@@ -965,18 +1744,20 @@ where
                 }
                 return None;
             }
-            EditorKey::CarriageReturn => {
-                if buf.len() != 0 {
-                    editor_set_status_msg(cfg, String::new());
-                    if let Some(cb) = callback.as_ref() {
-                        cb(cfg, &buf, key);
-                    }
-                    return Some(buf);
+            EditorKey::CarriageReturn if !buf.is_empty() => {
+                editor_set_status_msg(cfg, String::new());
+                if let Some(cb) = callback.as_ref() {
+                    cb(cfg, &buf, key);
                 }
+                return Some(buf);
             }
             EditorKey::Char(ch) => {
                 buf.push(ch);
             }
+            EditorKey::Paste(ref text) => {
+                let line = text.lines().next().unwrap_or("");
+                buf.extend(line.chars().filter(|c| !c.is_control()));
+            }
             EditorKey::DeleteKey | EditorKey::Backspace => {
                 buf.pop();
             }
@@ -998,90 +1779,379 @@ fn ctrl_key(k: char) -> char {
 /// Read a key and wait for the next one.
 ///
 /// It also handles keys with Escape sequences.
-fn editor_read_key() -> EditorKey {
-    let mut inp = io::stdin();
-    let mut c = [0];
-    loop {
-        let read_result = inp.read(&mut c);
-        if let Ok(n) = read_result {
-            if n > 0 {
-                break;
-            }
-        } else {
-            term_refresh();
-            exit(1);
-        }
-    }
-
-    let c = c[0];
-    let esc_seq = 0x1b;
-
-    if c == esc_seq {
-        let mut seq = [0 as u8; 3];
-        let mut handle = io::stdin().take(3);
-        handle.read(&mut seq).unwrap();
-        let seq0_char = seq[0] as char;
-        let seq1_char = seq[1] as char;
-        if seq0_char == '[' {
-            if seq[1] >= '0' as u8 && seq[1] <= '9' as u8 {
-                if seq[2] as char == '~' {
-                    return match seq1_char {
-                        '1' => EditorKey::HomeKey,
-                        '3' => EditorKey::DeleteKey,
-                        '4' => EditorKey::EndKey,
-                        '5' => EditorKey::PageUp,
-                        '6' => EditorKey::PageDown,
-                        '7' => EditorKey::HomeKey,
-                        '8' => EditorKey::EndKey,
-                        _ => EditorKey::EscapeSeq,
-                    };
-                }
-            } else {
-                return match seq1_char {
-                    'A' => EditorKey::ArrowUp,
-                    'B' => EditorKey::ArrowDown,
-                    'C' => EditorKey::ArrowRight,
-                    'D' => EditorKey::ArrowLeft,
-                    _ => EditorKey::EscapeSeq,
-                };
+fn editor_read_key(cfg: &mut EditorConfig) -> EditorKey {
+    let c = loop {
+        if HANGUP.load(Ordering::SeqCst) {
+            editor_hangup(cfg);
+        }
+        if CONTINUED.swap(false, Ordering::SeqCst) {
+            // Stopped from outside (e.g. `kill -STOP`), the shell may have
+            // reset the terminal modes meanwhile.
+            if let Err(e) = editor_init_terminal(cfg) {
+                editor_die(cfg, e);
             }
-        } else if seq0_char == '0' {
-            if seq1_char == 'H' {
-                return EditorKey::HomeKey;
-            } else if seq1_char == 'F' {
-                return EditorKey::EndKey;
-            } else {
+            return EditorKey::Resize;
+        }
+        if WINDOW_RESIZED.swap(false, Ordering::SeqCst) {
+            return EditorKey::Resize;
+        }
+        if editor_run_timers(cfg) {
+            return EditorKey::Timer;
+        }
+        if !editor_poll(cfg) {
+            continue;
+        }
+        match cfg.terminal.read_byte() {
+            Ok(Some(c)) => break c,
+            Ok(None) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                // Scripted input ran out: back out of any prompt and quit.
+                cfg.quit = true;
                 return EditorKey::EscapeSeq;
             }
+            Err(e) => editor_die(cfg, EditorError::Terminal(e)),
         }
-        return EditorKey::EscapeSeq;
-    } else if c == 127 {
-        return EditorKey::Backspace;
+    };
+
+    match c {
+        0x1b => editor_read_escape(cfg),
+        0x80..=0xff => editor_read_utf8(cfg, c),
+        _ => editor_decode_byte(c),
     }
-    let ch = c as char;
-    if ch.is_ascii_control() {
-        match ch {
-            '\n' | '\r' => EditorKey::CarriageReturn,
-            _ => EditorKey::Ctrl(ch),
+}
+
+/// Assemble a multi-byte UTF-8 sequence starting with `lead` into one key.
+///
+/// Malformed input comes out as U+FFFD rather than as stray Latin-1 chars.
+fn editor_read_utf8(cfg: &mut EditorConfig, lead: u8) -> EditorKey {
+    let invalid = EditorKey::Char(char::REPLACEMENT_CHARACTER);
+    let len = match lead {
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return invalid,
+    };
+
+    let mut buf = vec![lead];
+    while buf.len() < len {
+        match cfg.terminal.read_byte() {
+            Ok(Some(c)) if c & 0xc0 == 0x80 => buf.push(c),
+            _ => return invalid,
         }
+    }
+
+    match std::str::from_utf8(&buf)
+        .ok()
+        .and_then(|s| s.chars().next())
+    {
+        Some(ch) => EditorKey::Char(ch),
+        None => invalid,
+    }
+}
+
+/// Map a single byte of input to a key.
+fn editor_decode_byte(c: u8) -> EditorKey {
+    match c {
+        127 => EditorKey::Backspace,
+        b'\t' => EditorKey::Tab,
+        b'\n' | b'\r' => EditorKey::CarriageReturn,
+        _ if c.is_ascii_control() => EditorKey::Ctrl(c as char),
+        _ => EditorKey::Char(c as char),
+    }
+}
+
+/// Wrap `key` with modifier flags, leaving unmodified keys untouched.
+fn editor_modified_key(mods: u8, key: EditorKey) -> EditorKey {
+    if mods == 0 || key == EditorKey::EscapeSeq || key == EditorKey::Unknown {
+        key
     } else {
-        EditorKey::Char(ch)
+        EditorKey::Modified(mods, Box::new(key))
+    }
+}
+
+/// Decode whatever follows an ESC byte.
+///
+/// A bare Escape is told apart from the start of a sequence by the read
+/// timeout: if nothing follows the ESC in time it was the Escape key.
+fn editor_read_escape(cfg: &mut EditorConfig) -> EditorKey {
+    let c = match cfg.terminal.read_byte() {
+        Ok(Some(c)) => c,
+        _ => return EditorKey::EscapeSeq,
+    };
+
+    match c {
+        b'[' => editor_read_csi(cfg),
+        b'O' => match cfg.terminal.read_byte() {
+            Ok(Some(c)) => editor_csi_letter_key(c, 0),
+            _ => EditorKey::EscapeSeq,
+        },
+        0x1b => EditorKey::EscapeSeq,
+        0x80..=0xff => editor_modified_key(KeyModifier::Alt as u8, editor_read_utf8(cfg, c)),
+        _ => editor_modified_key(KeyModifier::Alt as u8, editor_decode_byte(c)),
+    }
+}
+
+/// Read a Control Sequence Introducer, `ESC [ params final`.
+fn editor_read_csi(cfg: &mut EditorConfig) -> EditorKey {
+    let mut params = String::new();
+    let last = loop {
+        match cfg.terminal.read_byte() {
+            Ok(Some(c)) if (0x40..=0x7e).contains(&c) => break c,
+            Ok(Some(c)) if params.len() < 32 => params.push(c as char),
+            _ => return EditorKey::Unknown,
+        }
+    };
+
+    if last == b'~' && params == "200" {
+        return editor_read_paste(cfg);
+    }
+    editor_decode_csi(&params, last)
+}
+
+/// Key sent as `ESC [ params last`.
+fn editor_decode_csi(params: &str, last: u8) -> EditorKey {
+    if let Some(report) = params.strip_prefix('<') {
+        return editor_decode_mouse(report, last);
+    }
+    if last == b'u' {
+        return editor_decode_kitty_key(params);
+    }
+
+    let nums: Vec<u32> = params.split(';').map(|n| n.parse().unwrap_or(0)).collect();
+    // xterm sends modifiers as `1 + flags` in the second parameter.
+    let mods = nums
+        .get(1)
+        .map_or(0, |&m| (m.saturating_sub(1) & 0b111) as u8);
+
+    match last {
+        b'~' => {
+            let key = match nums[0] {
+                1 | 7 => EditorKey::HomeKey,
+                2 => EditorKey::InsertKey,
+                3 => EditorKey::DeleteKey,
+                4 | 8 => EditorKey::EndKey,
+                5 => EditorKey::PageUp,
+                6 => EditorKey::PageDown,
+                11..=15 => EditorKey::Function((nums[0] - 10) as u8),
+                17..=21 => EditorKey::Function((nums[0] - 11) as u8),
+                23 | 24 => EditorKey::Function((nums[0] - 12) as u8),
+                _ => EditorKey::Unknown,
+            };
+            editor_modified_key(mods, key)
+        }
+        b'Z' => editor_modified_key(KeyModifier::Shift as u8, EditorKey::Tab),
+        _ => editor_csi_letter_key(last, mods),
+    }
+}
+
+/// Decode a kitty keyboard protocol event, `ESC [ code ; modifiers u`.
+///
+/// Ctrl with a key that has a C0 code comes out as `EditorKey::Ctrl`, like
+/// the legacy encoding, but Ctrl-I and Ctrl-M are now told apart from Tab
+/// and Enter.
+fn editor_decode_kitty_key(params: &str) -> EditorKey {
+    // Fields may carry `:` separated sub-fields, only the first one matters.
+    let mut fields = params
+        .split(';')
+        .map(|field| field.split(':').next().unwrap_or("").parse::<u32>().ok());
+    let code = fields.next().flatten();
+    let mut mods = fields
+        .next()
+        .flatten()
+        .map_or(0, |m| (m.saturating_sub(1) & 0b111) as u8);
+
+    let key = match code {
+        Some(27) => EditorKey::EscapeSeq,
+        Some(13) => EditorKey::CarriageReturn,
+        Some(9) => EditorKey::Tab,
+        Some(127) => EditorKey::Backspace,
+        // Functional keys without a legacy encoding live in the private use area.
+        Some(0xe000..=0xf8ff) | None => return EditorKey::Unknown,
+        Some(c) => match std::char::from_u32(c) {
+            Some(c) if !c.is_control() => EditorKey::Char(c),
+            _ => return EditorKey::Unknown,
+        },
+    };
+
+    let key = match key {
+        EditorKey::Char(c) if mods & KeyModifier::Ctrl as u8 != 0 && is_ctrl_char(c) => {
+            mods &= !(KeyModifier::Ctrl as u8);
+            EditorKey::Ctrl(ctrl_key(c))
+        }
+        key => key,
+    };
+    editor_modified_key(mods, key)
+}
+
+/// Whether Ctrl with `c` has a C0 control code, as in Ctrl-A or Ctrl-].
+fn is_ctrl_char(c: char) -> bool {
+    c.is_ascii_alphabetic() || "@[\\]^_".contains(c)
+}
+
+/// Keys identified by the final letter of a CSI or SS3 sequence.
+fn editor_csi_letter_key(c: u8, mods: u8) -> EditorKey {
+    let key = match c {
+        b'A' => EditorKey::ArrowUp,
+        b'B' => EditorKey::ArrowDown,
+        b'C' => EditorKey::ArrowRight,
+        b'D' => EditorKey::ArrowLeft,
+        b'H' => EditorKey::HomeKey,
+        b'F' => EditorKey::EndKey,
+        b'P'..=b'S' => EditorKey::Function(c - b'P' + 1),
+        _ => EditorKey::Unknown,
+    };
+    editor_modified_key(mods, key)
+}
+
+/// Decode an SGR mouse report, `ESC [ < button ; col ; row M`.
+///
+/// Coordinates are turned into zero based screen positions; release is
+/// reported with a trailing `m` instead of `M`.
+fn editor_decode_mouse(report: &str, last: u8) -> EditorKey {
+    let fields: Vec<usize> = report.split(';').filter_map(|n| n.parse().ok()).collect();
+    if fields.len() != 3 {
+        return EditorKey::Unknown;
+    }
+    let (button, x, y) = (
+        fields[0],
+        fields[1].saturating_sub(1),
+        fields[2].saturating_sub(1),
+    );
+
+    // Shift, Alt and Ctrl are reported in bits 2-4, motion in bit 5.
+    let motion = button & 32 != 0;
+    match button & !(4 | 8 | 16 | 32) {
+        64 => EditorKey::WheelUp,
+        65 => EditorKey::WheelDown,
+        0 if last == b'm' => EditorKey::MouseRelease(y, x),
+        0 if motion => EditorKey::MouseDrag(y, x),
+        0 => EditorKey::MousePress(y, x),
+        _ => EditorKey::Unknown,
+    }
+}
+
+/// Collect a bracketed paste up to the closing `ESC [ 201 ~`.
+fn editor_read_paste(cfg: &mut EditorConfig) -> EditorKey {
+    let end_marker = b"\x1b[201~";
+    let mut buf = Vec::new();
+    let mut timeouts = 0;
+
+    while !buf.ends_with(end_marker) && timeouts < KILO_PASTE_TIMEOUTS {
+        match cfg.terminal.read_byte() {
+            Ok(Some(c)) => {
+                timeouts = 0;
+                // Past the limit only enough is kept to spot the end marker.
+                if buf.len() == KILO_PASTE_MAX + end_marker.len() {
+                    buf.remove(KILO_PASTE_MAX);
+                }
+                buf.push(c);
+            }
+            Ok(None) => timeouts += 1,
+            Err(_) => break,
+        }
+    }
+    if buf.ends_with(end_marker) {
+        buf.truncate(buf.len() - end_marker.len());
+    }
+    buf.truncate(KILO_PASTE_MAX);
+
+    let text = String::from_utf8_lossy(&buf)
+        .replace("\r\n", "\n")
+        .replace('\r', "\n");
+    EditorKey::Paste(text)
+}
+
+/// Drop back to the shell, picking up where we left off on `fg`.
+fn editor_suspend(cfg: &mut EditorConfig) -> Result<()> {
+    editor_restore_terminal(cfg)?;
+    let suspended = cfg.terminal.suspend().map_err(EditorError::Terminal);
+
+    // Execution continues here once SIGCONT arrives.
+    CONTINUED.store(false, Ordering::SeqCst);
+    editor_init_terminal(cfg)?;
+    editor_handle_resize(cfg);
+    suspended
+}
+
+/// Edit until the user quits.
+fn editor_run(cfg: &mut EditorConfig) {
+    while !cfg.quit {
+        if let Err(e) = editor_refresh_screen(cfg) {
+            editor_die(cfg, e);
+        }
+        editor_process_keypress(cfg);
     }
 }
 
 fn exit_gracefully(cfg: &mut EditorConfig) {
-    term_refresh();
-    disable_raw_mode(&cfg.term).unwrap();
+    editor_remove_swap(cfg);
+    if let Err(e) = editor_restore_terminal(cfg) {
+        eprintln!("kilo: {}", e);
+        exit(1);
+    }
     exit(0);
 }
 
+/// Whether `key` changes the buffer or writes it out.
+fn editor_key_edits(key: &EditorKey) -> bool {
+    match key {
+        EditorKey::Char(_)
+        | EditorKey::Tab
+        | EditorKey::Paste(_)
+        | EditorKey::CarriageReturn
+        | EditorKey::DeleteKey
+        | EditorKey::Backspace => true,
+        EditorKey::Ctrl(c) => "xvsur".chars().any(|k| *c == ctrl_key(k)),
+        EditorKey::Modified(mods, key) => {
+            *mods == KeyModifier::Alt as u8
+                && matches!(**key, EditorKey::Char('-' | '=' | ',' | '.' | 't'))
+        }
+        _ => false,
+    }
+}
+
 fn editor_process_keypress(cfg: &mut EditorConfig) {
-    let c = editor_read_key();
+    let c = editor_read_key(cfg);
+
+    match c {
+        EditorKey::Timer | EditorKey::Unknown => return,
+        EditorKey::Resize
+        | EditorKey::MouseDrag(..)
+        | EditorKey::MouseRelease(..)
+        | EditorKey::WheelUp
+        | EditorKey::WheelDown => (),
+        EditorKey::Modified(mods, _) if mods & KeyModifier::Shift as u8 != 0 => (),
+        EditorKey::Ctrl(c) if c == ctrl_key('c') || c == ctrl_key('x') => (),
+        _ => cfg.selection_anchor = None,
+    }
+
+    if cfg.buffer.is_read_only() && editor_key_edits(&c) {
+        editor_set_status_msg(cfg, "Read-only view, the file can't be changed".to_string());
+        return;
+    }
 
     match c {
+        EditorKey::Resize => {
+            editor_handle_resize(cfg);
+        }
+        EditorKey::MousePress(y, x) => {
+            editor_move_to_screen_pos(cfg, y, x);
+            cfg.selection_anchor = Some((cfg.cy, cfg.cx));
+        }
+        EditorKey::MouseDrag(y, x) => {
+            editor_move_to_screen_pos(cfg, y, x);
+        }
+        EditorKey::MouseRelease(y, x) => {
+            editor_move_to_screen_pos(cfg, y, x);
+            if cfg.selection_anchor == Some((cfg.cy, cfg.cx)) {
+                cfg.selection_anchor = None;
+            }
+        }
+        EditorKey::WheelUp | EditorKey::WheelDown => {
+            editor_scroll_view(cfg, c == EditorKey::WheelDown);
+        }
         EditorKey::CarriageReturn => {
-            editor_move_cursor(cfg, EditorKey::ArrowRight);
-            editor_insert_new_line(cfg);
+            editor_edit(cfg, Group::Command, editor_insert_new_line);
         }
         EditorKey::ArrowUp
         | EditorKey::ArrowDown
@@ -1094,8 +2164,8 @@ fn editor_process_keypress(cfg: &mut EditorConfig) {
                 cfg.cy = cfg.rowoff;
             } else if c == EditorKey::PageDown {
                 cfg.cy = cfg.rowoff + cfg.screenrows - 1;
-                if cfg.cy > cfg.numrows {
-                    cfg.cy = cfg.numrows;
+                if cfg.cy > cfg.buffer.len() {
+                    cfg.cy = cfg.buffer.len();
                 }
             }
 
@@ -1113,9 +2183,7 @@ fn editor_process_keypress(cfg: &mut EditorConfig) {
             cfg.cx = 0;
         }
         EditorKey::EndKey => {
-            if cfg.cy < cfg.numrows {
-                cfg.cx = cfg.rows[cfg.cy].chars.len();
-            }
+            cfg.cx = cfg.buffer.line_len(cfg.cy);
         }
         EditorKey::Ctrl(c) => {
             if c == ctrl_key('q') {
@@ -1123,106 +2191,282 @@ fn editor_process_keypress(cfg: &mut EditorConfig) {
                     editor_set_status_msg(
                         cfg,
                         format!(
-                            "\x1b[31mWARNING!!! File has unsaved changes. \
-                            Press Ctrl-Q {} more times to quit.\x1b[39m",
+                            "WARNING!!! File has unsaved changes. \
+                            Press Ctrl-Q {} more times to quit.",
                             cfg.quit_times,
                         ),
                     );
                     cfg.quit_times -= 1;
                     return;
                 }
-                exit_gracefully(cfg);
+                cfg.quit = true;
             } else if c == ctrl_key('s') {
                 editor_save(cfg);
             } else if c == ctrl_key('f') {
                 editor_find(cfg);
+            } else if c == ctrl_key('z') {
+                if let Err(e) = editor_suspend(cfg) {
+                    editor_set_status_msg(cfg, format!("Can't suspend: {}", e));
+                }
+            } else if c == ctrl_key('c') {
+                editor_copy(cfg);
+            } else if c == ctrl_key('x') {
+                editor_edit(cfg, Group::Command, editor_cut);
+            } else if c == ctrl_key('v') {
+                editor_edit(cfg, Group::Command, editor_paste);
+            } else if c == ctrl_key('u') {
+                editor_history_step(cfg, History::undo, "Nothing to undo");
+            } else if c == ctrl_key('r') {
+                editor_history_step(cfg, History::redo, "Nothing to redo");
+            } else if c == ctrl_key('t') {
+                cfg.theme = (cfg.theme + 1) % THEMES.len();
+                let name = THEMES[cfg.theme].name;
+                editor_set_status_msg(cfg, format!("Theme: {}", name));
             }
         }
+        EditorKey::Modified(mods, key) => match *key {
+            EditorKey::Char(c) if mods == KeyModifier::Alt as u8 => {
+                editor_history_command(cfg, c);
+            }
+            key => editor_move_modified(cfg, mods, key),
+        },
         EditorKey::Char(c) => {
-            editor_insert_char(cfg, c);
+            editor_edit(cfg, Group::Typing, |cfg| editor_insert_char(cfg, c));
+        }
+        EditorKey::Tab => {
+            editor_edit(cfg, Group::Typing, |cfg| editor_insert_char(cfg, '\t'));
+        }
+        EditorKey::Paste(text) => {
+            editor_edit(cfg, Group::Command, |cfg| editor_insert_text(cfg, &text));
         }
         EditorKey::DeleteKey | EditorKey::Backspace => {
-            editor_del_char(cfg);
+            editor_edit(cfg, Group::Deleting, editor_del_char);
         }
         _ => (),
     }
 }
 
 fn editor_move_cursor(cfg: &mut EditorConfig, key: EditorKey) {
-    let mut row = &Row::default();
-    if cfg.cy < cfg.numrows {
-        row = &cfg.rows[cfg.cy];
-    }
+    let chars = cfg.buffer.line(cfg.cy);
 
     match key {
         EditorKey::ArrowLeft => {
             if cfg.cx != 0 {
-                cfg.cx -= 1;
+                cfg.cx = prev_grapheme(&chars, cfg.cx);
             } else if cfg.cy > 0 {
                 cfg.cy -= 1;
-                cfg.cx = cfg.rows[cfg.cy].chars.len();
+                cfg.cx = cfg.buffer.line_len(cfg.cy);
             }
         }
-        EditorKey::ArrowRight => {
-            if cfg.cx < row.chars.len() {
-                cfg.cx += 1;
-            } else if cfg.cx == row.chars.len() {
+        EditorKey::ArrowRight if cfg.cy < cfg.buffer.len() => {
+            if cfg.cx < chars.len() {
+                cfg.cx = next_grapheme(&chars, cfg.cx);
+            } else {
                 cfg.cy += 1;
                 cfg.cx = 0;
             }
         }
-        EditorKey::ArrowUp => {
-            if cfg.cy != 0 {
+        EditorKey::ArrowUp | EditorKey::ArrowDown => {
+            // Keep the screen column, not the byte offset, when changing rows.
+            let rx = editor_row_cx_to_rx(&chars, cfg.cx);
+            if key == EditorKey::ArrowUp && cfg.cy != 0 {
                 cfg.cy -= 1;
-            }
-        }
-        EditorKey::ArrowDown => {
-            if cfg.cy < cfg.numrows {
+            } else if key == EditorKey::ArrowDown && cfg.cy < cfg.buffer.len() {
                 cfg.cy += 1;
             }
+            cfg.cx = editor_row_rx_to_cx(&cfg.buffer.line(cfg.cy), rx);
         }
         _ => (),
     }
-    if cfg.cy < cfg.numrows {
-        row = &cfg.rows[cfg.cy];
+
+    let rowlen = cfg.buffer.line_len(cfg.cy);
+    if cfg.cx > rowlen {
+        cfg.cx = rowlen;
     }
+}
+
+/// Place the cursor on the buffer position shown at screen `(y, x)`.
+fn editor_move_to_screen_pos(cfg: &mut EditorConfig, y: usize, x: usize) {
+    if y >= cfg.screenrows || x >= editor_text_cols(cfg) {
+        return;
+    }
+
+    cfg.cy = (y + cfg.rowoff).min(cfg.buffer.len());
+    cfg.cx = editor_row_rx_to_cx(&cfg.buffer.line(cfg.cy), x + cfg.coloff);
+}
 
-    let rowlen = row.chars.len();
+/// Scroll the view a few lines, dragging the cursor along when it would
+/// otherwise leave the screen.
+fn editor_scroll_view(cfg: &mut EditorConfig, down: bool) {
+    if down {
+        cfg.rowoff = (cfg.rowoff + KILO_SCROLL_LINES).min(cfg.buffer.len().saturating_sub(1));
+        if cfg.cy < cfg.rowoff {
+            cfg.cy = cfg.rowoff;
+        }
+    } else {
+        cfg.rowoff = cfg.rowoff.saturating_sub(KILO_SCROLL_LINES);
+        if cfg.cy >= cfg.rowoff + cfg.screenrows {
+            cfg.cy = cfg.rowoff + cfg.screenrows.saturating_sub(1);
+        }
+    }
+
+    let rowlen = cfg.buffer.line_len(cfg.cy);
     if cfg.cx > rowlen {
         cfg.cx = rowlen;
     }
 }
 
-// *** File I/O ***
+/// Movement keys combined with modifiers.
+///
+/// Shift extends the selection, Ctrl moves by words on Left/Right and to
+/// the start or end of the buffer on Home/End.
+fn editor_move_modified(cfg: &mut EditorConfig, mods: u8, key: EditorKey) {
+    if mods & KeyModifier::Shift as u8 != 0 && cfg.selection_anchor.is_none() {
+        cfg.selection_anchor = Some((cfg.cy, cfg.cx));
+    }
+    let ctrl = mods & KeyModifier::Ctrl as u8 != 0;
 
-fn editor_open(cfg: &mut EditorConfig, filename: &str) {
-    cfg.filename = Some(filename.to_string());
-    editor_select_syntax_highlight(cfg);
-    let mut file = File::open(filename).unwrap();
-    let mut file_content = Vec::new();
-    match file.read_to_end(&mut file_content) {
-        Ok(_) => {
-            let lossy_content = String::from_utf8_lossy(&file_content);
-            for (i, line) in lossy_content.lines().enumerate() {
-                editor_insert_row(cfg, line.to_string(), i);
+    match key {
+        EditorKey::ArrowLeft | EditorKey::ArrowRight if ctrl => {
+            editor_move_word(cfg, key == EditorKey::ArrowRight);
+        }
+        EditorKey::ArrowUp
+        | EditorKey::ArrowDown
+        | EditorKey::ArrowLeft
+        | EditorKey::ArrowRight => {
+            editor_move_cursor(cfg, key);
+        }
+        EditorKey::HomeKey => {
+            if ctrl {
+                cfg.cy = 0;
             }
-            cfg.dirty = false;
+            cfg.cx = 0;
         }
-        Err(e) => {
-            eprint!("{:?}", e);
-            exit_gracefully(cfg);
+        EditorKey::EndKey => {
+            if ctrl {
+                cfg.cy = cfg.buffer.len().saturating_sub(1);
+            }
+            cfg.cx = cfg.buffer.line_len(cfg.cy);
         }
+        _ => (),
     }
 }
 
-fn editor_rows_to_string(cfg: &EditorConfig) -> String {
-    let mut buf = String::new();
-    for row in &cfg.rows {
-        buf.push_str(&row.chars);
-        buf.push('\n');
+/// Move to the start of the previous word or past the end of the next one.
+fn editor_move_word(cfg: &mut EditorConfig, forward: bool) {
+    if cfg.cy >= cfg.buffer.len() {
+        editor_move_cursor(cfg, EditorKey::ArrowLeft);
+        return;
+    }
+
+    let is_word = |g: &str| {
+        g.chars()
+            .next()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_')
+    };
+    let chars = cfg.buffer.line(cfg.cy);
+    let cx = cfg.cx;
+    if forward {
+        if cx >= chars.len() {
+            editor_move_cursor(cfg, EditorKey::ArrowRight);
+            return;
+        }
+        cfg.cx = chars[cx..]
+            .grapheme_indices(true)
+            .skip_while(|(_, g)| !is_word(g))
+            .find(|(_, g)| !is_word(g))
+            .map_or(chars.len(), |(i, _)| cx + i);
+    } else {
+        if cx == 0 {
+            editor_move_cursor(cfg, EditorKey::ArrowLeft);
+            return;
+        }
+        cfg.cx = chars[..cx]
+            .grapheme_indices(true)
+            .rev()
+            .skip_while(|(_, g)| !is_word(g))
+            .find(|(_, g)| !is_word(g))
+            .map_or(0, |(i, g)| i + g.len());
+    }
+}
+
+// *** File I/O ***
+
+/// Open `filename` for editing.
+///
+/// A file that doesn't exist yet gives an empty buffer that will be saved
+/// under that name. Files of `view_threshold` bytes and more are only
+/// viewed.
+fn editor_open(cfg: &mut EditorConfig, filename: &str) -> Result<()> {
+    let file_error = |e| EditorError::File(filename.to_string(), e);
+    let mut content = Vec::new();
+    match fs::File::open(filename) {
+        Ok(mut file) => {
+            let metadata = file.metadata().map_err(file_error)?;
+            if metadata.is_file() && metadata.len() >= cfg.view_threshold {
+                return editor_view(cfg, filename, &file);
+            }
+            file.read_to_end(&mut content).map_err(file_error)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(file_error(e)),
+    }
+
+    cfg.filename = Some(filename.to_string());
+    editor_select_syntax_highlight(cfg);
+    editor_load(cfg, &content);
+    Ok(())
+}
+
+/// Show `file` read-only, mapped into memory instead of loaded.
+///
+/// There is no syntax highlighting, it would have to go through the whole
+/// file to find the comments.
+fn editor_view(cfg: &mut EditorConfig, filename: &str, file: &fs::File) -> Result<()> {
+    cfg.buffer = Buffer::map(file).map_err(|e| EditorError::File(filename.to_string(), e))?;
+    cfg.filename = Some(filename.to_string());
+    editor_invalidate_rows(cfg, 0);
+    editor_set_timer(cfg, Timer::Index, KILO_INDEX_INTERVAL);
+    editor_set_status_msg(
+        cfg,
+        "File too big to edit, viewing it read-only".to_string(),
+    );
+    Ok(())
+}
+
+/// Replace the buffer with `content`, leaving it clean.
+///
+/// Rows are rendered and highlighted only once they come into view.
+fn editor_load(cfg: &mut EditorConfig, content: &[u8]) {
+    cfg.buffer = Buffer::from_text(&String::from_utf8_lossy(content));
+    editor_invalidate_rows(cfg, 0);
+    cfg.history = History::new();
+    cfg.dirty = false;
+    editor_cancel_timer(cfg, Timer::Swap);
+}
+
+/// Note that the buffer has changes that aren't saved yet.
+///
+/// Changes get to the swap file within `KILO_SWAP_INTERVAL`.
+fn editor_set_dirty(cfg: &mut EditorConfig) {
+    cfg.dirty = true;
+    cfg.swap_stale = true;
+    if !cfg.timers.iter().any(|(_, t)| *t == Timer::Swap) {
+        editor_set_timer(cfg, Timer::Swap, KILO_SWAP_INTERVAL);
     }
+}
 
-    buf
+/// Slurp a document piped in on stdin.
+///
+/// This has to happen before the terminal is set up, as the producer on the
+/// other end of the pipe may still be writing to the screen.
+fn read_stdin() -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    io::stdin()
+        .read_to_end(&mut content)
+        .map_err(|e| EditorError::File("stdin".to_string(), e))?;
+
+    Ok(content)
 }
 
 fn editor_save(cfg: &mut EditorConfig) {
@@ -1238,46 +2482,744 @@ fn editor_save(cfg: &mut EditorConfig) {
         editor_select_syntax_highlight(cfg);
     }
 
-    if let Some(filename) = cfg.filename.as_ref() {
-        let buf = editor_rows_to_string(cfg);
-        let mut fd = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)
-            .unwrap();
-
-        match fd.write(buf.as_bytes()) {
+    if let Some(filename) = cfg.filename.clone() {
+        match editor_write_file(cfg, &filename) {
             Ok(n) => {
+                cfg.history.mark_saved();
                 cfg.dirty = false;
+                editor_cancel_timer(cfg, Timer::Swap);
+                editor_remove_swap(cfg);
                 editor_set_status_msg(cfg, format!("{} bytes written to disk", n));
             }
-            Err(e) => editor_set_status_msg(cfg, format!("Can't save I/O error: {}", e)),
+            Err(e) => editor_set_status_msg(cfg, format!("Can't save! {}", e)),
+        }
+    }
+}
+
+/// Write the buffer to `filename`, returns the number of bytes written.
+///
+/// The buffer goes to a temporary file next to the original first, which
+/// then replaces it in one rename, so a failed or interrupted save never
+/// leaves a half written file behind. Mode and ownership of the original
+/// are carried over.
+fn editor_write_file(cfg: &EditorConfig, filename: &str) -> Result<usize> {
+    let file_error = |e| EditorError::File(filename.to_string(), e);
+    // Write through symlinks instead of replacing them.
+    let target = fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+    let original = match fs::metadata(&target) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(file_error(e)),
+    };
+
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = target.file_name().unwrap_or(target.as_os_str());
+    // A new file gets the usual mode after the umask, an existing one keeps
+    // its own; until then nobody else gets to look at the contents.
+    let mode = if original.is_some() { 0o600 } else { 0o666 };
+    let (tmp, file) = create_tmp(dir, &name.to_string_lossy(), mode).map_err(file_error)?;
+
+    let result = editor_write_tmp(cfg, filename, file, original.as_ref()).and_then(|n| {
+        fs::rename(&tmp, &target).map_err(file_error)?;
+        Ok(n)
+    });
+    match result {
+        Ok(_) => {
+            // Make the rename itself durable, the data already is.
+            if let Ok(dir) = fs::File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
+        Err(_) => {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+    result
+}
+
+/// Create a temporary file for `name` in `dir`, e.g. `.main.rs.kilo-42.tmp`.
+///
+/// One left behind by a crashed editor that had the same pid is passed
+/// over for a name with a counter added.
+fn create_tmp(dir: &Path, name: &str, mode: u32) -> io::Result<(PathBuf, fs::File)> {
+    let pid = std::process::id();
+    let mut n = 0;
+    loop {
+        let tmp = if n == 0 {
+            dir.join(format!(".{}.kilo-{}.tmp", name, pid))
+        } else {
+            dir.join(format!(".{}.kilo-{}-{}.tmp", name, pid, n))
+        };
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmp)
+        {
+            Ok(file) => return Ok((tmp, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && n < 100 => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Write the buffer to the new temporary `file` and sync it to disk,
+/// giving it the mode and owner of `original`.
+fn editor_write_tmp(
+    cfg: &EditorConfig,
+    filename: &str,
+    mut file: fs::File,
+    original: Option<&fs::Metadata>,
+) -> Result<usize> {
+    let file_error = |e| EditorError::File(filename.to_string(), e);
+    let buf = cfg.buffer.text();
+    let partial =
+        |written, e| EditorError::PartialWrite(filename.to_string(), written, buf.len(), e);
+
+    if let Some(metadata) = original {
+        // Only root may give files away, keep whatever we are allowed to.
+        if unix_fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid())).is_err() {
+            let _ = unix_fs::fchown(&file, None, Some(metadata.gid()));
         }
+        file.set_permissions(metadata.permissions())
+            .map_err(file_error)?;
+    }
+
+    let mut written = 0;
+    while written < buf.len() {
+        match file.write(&buf.as_bytes()[written..]) {
+            Ok(0) => return Err(partial(written, io::ErrorKind::WriteZero.into())),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(partial(written, e)),
+        }
+    }
+    file.sync_all().map_err(file_error)?;
+
+    Ok(written)
+}
+
+// *** Swap file ***
+
+/// First line of a swap file, followed by the pid of its editor.
+const KILO_SWAP_HEADER: &str = "kilo swap file, pid ";
+
+/// Swap file kept next to `filename`, e.g. `.main.rs.kilo.swp`.
+fn swap_path(filename: &str) -> PathBuf {
+    let path = Path::new(filename);
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    path.with_file_name(format!(".{}.kilo.swp", name.to_string_lossy()))
+}
+
+/// Save the unsaved changes, so they survive a crash or a dropped session.
+///
+/// Writing out a big file takes a while, so it is done in the background
+/// from a copy of the buffer.
+fn editor_write_swap(cfg: &mut EditorConfig) {
+    editor_wait_swap(cfg);
+    let path = match cfg.filename.as_ref() {
+        Some(filename) if cfg.dirty && cfg.swap_enabled && cfg.swap_stale => swap_path(filename),
+        _ => return,
+    };
+
+    cfg.swap_stale = false;
+    let header = format!("{}{}\n", KILO_SWAP_HEADER, std::process::id());
+    let buffer = cfg.buffer.clone();
+    cfg.swap_writer = Some(thread::spawn(move || {
+        write_swap(&path, &header, &buffer)
+            .map_err(|e| EditorError::File(path.display().to_string(), e))
+    }));
+    editor_set_timer(cfg, Timer::SwapWritten, KILO_SWAP_POLL_INTERVAL);
+}
+
+/// Wait for the swap file write in the background, if any, to be done.
+fn editor_wait_swap(cfg: &mut EditorConfig) {
+    let writer = match cfg.swap_writer.take() {
+        Some(writer) => writer,
+        None => return,
+    };
+    editor_cancel_timer(cfg, Timer::SwapWritten);
+    if let Ok(Err(e)) = writer.join() {
+        // Don't nag on every change, one message is enough.
+        cfg.swap_enabled = false;
+        editor_set_status_msg(cfg, format!("Swap file disabled! {}", e));
+    }
+}
 
-        if let Err(e) = fd.flush() {
-            editor_set_status_msg(cfg, format!("Can't save I/O error: {}", e));
+/// Replace the swap file at `path` with `header` followed by the text of
+/// `buffer` in one rename, so a crash never leaves half of it behind.
+///
+/// Only the owner may read it, whatever the mode of the file it is for.
+fn write_swap(path: &Path, header: &str, buffer: &Buffer) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    let (tmp, file) = create_tmp(dir, &name.to_string_lossy(), 0o600)?;
+
+    let mut out = io::BufWriter::new(file);
+    let result = out
+        .write_all(header.as_bytes())
+        .and_then(|()| buffer.write_to(&mut out))
+        .and_then(|()| out.flush())
+        .and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn editor_remove_swap(cfg: &mut EditorConfig) {
+    // A write still going on would put the swap file back.
+    editor_wait_swap(cfg);
+    if let Some(filename) = cfg.filename.as_ref() {
+        if cfg.swap_enabled {
+            let _ = fs::remove_file(swap_path(filename));
         }
     }
 }
 
+/// Save what we can and leave when the terminal is gone.
+fn editor_hangup(cfg: &mut EditorConfig) -> ! {
+    if cfg.dirty {
+        editor_write_swap(cfg);
+        editor_wait_swap(cfg);
+    } else {
+        editor_remove_swap(cfg);
+    }
+    let _ = editor_restore_terminal(cfg);
+    exit(1);
+}
+
+/// Look for a swap file left behind for the file just opened and ask what
+/// to do with it.
+fn editor_check_swap(cfg: &mut EditorConfig) {
+    let filename = match cfg.filename.clone() {
+        Some(filename) => filename,
+        None => return,
+    };
+    let path = swap_path(&filename);
+    let content = match fs::read(&path) {
+        Ok(content) => String::from_utf8_lossy(&content).to_string(),
+        Err(_) => return,
+    };
+
+    let (pid, text) = parse_swap(&content);
+    if let Some(pid) = pid {
+        // Signal 0 only checks whether the process is there.
+        let alive = !matches!(
+            signal::kill(unistd::Pid::from_raw(pid), None),
+            Err(nix::Error::Sys(Errno::ESRCH))
+        );
+        if alive && pid != std::process::id() as i32 {
+            cfg.swap_enabled = false;
+            editor_set_status_msg(
+                cfg,
+                format!(
+                    "{} is being edited by kilo pid {}, no swap file",
+                    filename, pid
+                ),
+            );
+            return;
+        }
+    }
+
+    loop {
+        editor_set_status_msg(
+            cfg,
+            "Swap file found! r = recover, d = diff, x = discard, ESC = keep".to_string(),
+        );
+        if let Err(e) = editor_refresh_screen(cfg) {
+            editor_die(cfg, e);
+        }
+        match editor_read_key(cfg) {
+            EditorKey::Char('r') => {
+                editor_replace_buffer(cfg, text);
+                // None of the states from here on is what is on disk.
+                cfg.history.mark_unsaved();
+                editor_set_dirty(cfg);
+                editor_set_status_msg(cfg, "Recovered from the swap file".to_string());
+                return;
+            }
+            EditorKey::Char('d') => editor_show_swap_diff(cfg, text),
+            EditorKey::Char('x') => {
+                let _ = fs::remove_file(&path);
+                editor_set_status_msg(cfg, "Swap file discarded".to_string());
+                return;
+            }
+            EditorKey::EscapeSeq => {
+                cfg.swap_enabled = false;
+                editor_set_status_msg(cfg, "Swap file kept, edits are not swapped".to_string());
+                return;
+            }
+            EditorKey::Resize => editor_handle_resize(cfg),
+            _ => (),
+        }
+    }
+}
+
+/// Pid of the editor that wrote swap file `content`, if its header names
+/// one, and the text the swap file holds.
+fn parse_swap(content: &str) -> (Option<i32>, &str) {
+    let (header, text) = content.split_at(content.find('\n').map_or(0, |i| i + 1));
+    let pid = header
+        .trim_end()
+        .strip_prefix(KILO_SWAP_HEADER)
+        .and_then(|pid| pid.parse::<i32>().ok());
+    (pid, text)
+}
+
+/// Throw away the buffer and load `text` instead.
+fn editor_replace_buffer(cfg: &mut EditorConfig, text: &str) {
+    cfg.cx = 0;
+    cfg.cy = 0;
+    cfg.rowoff = 0;
+    cfg.coloff = 0;
+    editor_load(cfg, text.as_bytes());
+}
+
+/// Show how the swap file differs from the file, until a key is pressed.
+fn editor_show_swap_diff(cfg: &mut EditorConfig, swap: &str) {
+    let file = cfg.buffer.text();
+    let diff = diff_lines(&file, swap);
+
+    let saved_buffer = std::mem::replace(&mut cfg.buffer, Buffer::new());
+    let saved_history = std::mem::replace(&mut cfg.history, History::new());
+    let saved_syntax = cfg.editor_syntax.take();
+    let saved_pos = (cfg.cx, cfg.cy, cfg.rowoff, cfg.coloff, cfg.dirty);
+    editor_replace_buffer(cfg, &diff);
+
+    loop {
+        editor_set_status_msg(
+            cfg,
+            "Diff against the swap file, arrows to scroll, any other key to go back".to_string(),
+        );
+        if let Err(e) = editor_refresh_screen(cfg) {
+            editor_die(cfg, e);
+        }
+        match editor_read_key(cfg) {
+            key @ EditorKey::ArrowUp
+            | key @ EditorKey::ArrowDown
+            | key @ EditorKey::ArrowLeft
+            | key @ EditorKey::ArrowRight => editor_move_cursor(cfg, key),
+            EditorKey::Resize => editor_handle_resize(cfg),
+            EditorKey::Timer | EditorKey::Unknown => (),
+            _ => break,
+        }
+    }
+
+    cfg.buffer = saved_buffer;
+    cfg.history = saved_history;
+    cfg.editor_syntax = saved_syntax;
+    editor_invalidate_rows(cfg, 0);
+    (cfg.cx, cfg.cy, cfg.rowoff, cfg.coloff, cfg.dirty) = saved_pos;
+}
+
+/// Unified style diff of two texts.
+///
+/// Only the common lines at either end are matched up, which is plenty to
+/// see what a swap file would bring back.
+fn diff_lines(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    if prefix == old.len() && prefix == new.len() {
+        return "The swap file has no changes".to_string();
+    }
+    let mut diff = format!(
+        "@@ -{},{} +{},{} @@\n",
+        prefix + 1,
+        old.len() - prefix - suffix,
+        prefix + 1,
+        new.len() - prefix - suffix
+    );
+    for line in &old[prefix..old.len() - suffix] {
+        diff.push_str(&format!("-{}\n", line));
+    }
+    for line in &new[prefix..new.len() - suffix] {
+        diff.push_str(&format!("+{}\n", line));
+    }
+    diff
+}
+
+/// Report an error from before the terminal was set up.
+fn fail(err: EditorError) -> ! {
+    eprintln!("kilo: {}", err);
+    exit(1);
+}
+
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
-    let mut cfg = EditorConfig::new();
-    enable_raw_mode(&cfg).unwrap();
+    let from_stdin = match args.get(1) {
+        Some(arg) => arg == "-",
+        None => !unistd::isatty(io::stdin().as_raw_fd()).unwrap_or(true),
+    };
+    let piped = if from_stdin {
+        Some(read_stdin().unwrap_or_else(|e| fail(e)))
+    } else {
+        None
+    };
 
-    if args.len() > 1 {
-        let filename = &args[1];
-        editor_open(&mut cfg, filename);
+    let terminal = TtyTerminal::new()
+        .map_err(EditorError::Terminal)
+        .unwrap_or_else(|e| fail(e));
+    let mut cfg = EditorConfig::new(Box::new(terminal)).unwrap_or_else(|e| fail(e));
+    terminal::install_panic_hook();
+    if let Err(e) = editor_init_terminal(&mut cfg) {
+        editor_die(&mut cfg, e);
+    }
+    if let Err(e) = editor_enable_kitty_keyboard(&mut cfg) {
+        editor_die(&mut cfg, e);
+    }
+    match install_signal_handlers() {
+        Ok(fd) => cfg.signal_fd = Some(fd),
+        Err(e) => editor_die(&mut cfg, e.into()),
     }
 
     editor_set_status_msg(
         &mut cfg,
-        "HELP: Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find".to_string(),
+        "HELP: Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find | Ctrl-U = undo | Alt-H = history"
+            .to_string(),
     );
 
-    loop {
-        editor_refresh_screen(&mut cfg);
-        editor_process_keypress(&mut cfg);
+    if let Some(content) = piped {
+        editor_load(&mut cfg, &content);
+    } else if args.len() > 1 {
+        let filename = &args[1];
+        match editor_open(&mut cfg, filename) {
+            Ok(()) if !cfg.buffer.is_read_only() => editor_check_swap(&mut cfg),
+            Ok(()) => (),
+            Err(e) => editor_set_status_msg(&mut cfg, format!("Can't open {}", e)),
+        }
+    }
+
+    editor_run(&mut cfg);
+    exit_gracefully(&mut cfg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::os::unix::fs::PermissionsExt;
+    use std::rc::Rc;
+    use terminal::VirtualScreen;
+
+    /// Editor started up on a virtual screen of `rows` by `cols`.
+    fn editor_on_screen(rows: usize, cols: usize) -> (EditorConfig, Rc<RefCell<VirtualScreen>>) {
+        let screen = Rc::new(RefCell::new(VirtualScreen::new(rows, cols)));
+        let mut cfg = EditorConfig::new(Box::new(screen.clone())).unwrap();
+        editor_init_terminal(&mut cfg).unwrap();
+        (cfg, screen)
+    }
+
+    /// Empty directory of its own for a test to write files in.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("kilo-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Type `keys` and let the editor handle them all.
+    fn editor_type(cfg: &mut EditorConfig, screen: &Rc<RefCell<VirtualScreen>>, keys: &str) {
+        screen.borrow_mut().feed_keys(keys.as_bytes());
+        cfg.quit = false;
+        editor_run(cfg);
+    }
+
+    #[test]
+    fn shows_the_welcome_screen() {
+        let (mut cfg, screen) = editor_on_screen(6, 40);
+        editor_type(&mut cfg, &screen, "");
+
+        let screen = screen.borrow();
+        let welcome = format!("~     Kilo editor -- version {}", env!("CARGO_PKG_VERSION"));
+        let expected = [
+            "~",
+            welcome.as_str(),
+            "~",
+            "~",
+            "[No Name] - 0 lines          no ft | 1/0",
+            "",
+        ];
+        assert_eq!(screen.snapshot(), expected.join("\n"));
+        assert!(screen.is_raw() && screen.is_alternate_screen());
+        assert_eq!(screen.title(), "[No Name] — kilo");
+        assert_eq!(screen.cursor(), (0, 0));
+        assert!(screen.cursor_visible());
+    }
+
+    #[test]
+    fn typing_shows_up_on_screen() {
+        let (mut cfg, screen) = editor_on_screen(6, 40);
+        editor_type(&mut cfg, &screen, "hello\rworld\x7f");
+
+        let screen = screen.borrow();
+        assert_eq!(screen.line(0), "hello");
+        assert_eq!(screen.line(1), "worl");
+        assert_eq!(screen.line(2), "~");
+        assert!(screen.line(4).contains("(modified)"));
+        assert_eq!(screen.cursor(), (1, 4));
+        assert_eq!(screen.title(), "* [No Name] — kilo");
+    }
+
+    /// Snapshot and cursor once the whole screen is drawn from scratch.
+    fn redrawn(
+        cfg: &mut EditorConfig,
+        screen: &Rc<RefCell<VirtualScreen>>,
+    ) -> (String, (usize, usize)) {
+        cfg.full_redraw = true;
+        editor_refresh_screen(cfg).unwrap();
+        let screen = screen.borrow();
+        (screen.snapshot(), screen.cursor())
+    }
+
+    #[test]
+    fn wide_text_takes_two_cells() {
+        let (mut cfg, screen) = editor_on_screen(6, 40);
+        editor_type(&mut cfg, &screen, "日本語abc");
+        assert_eq!(screen.borrow().line(0), "日本語abc");
+        assert_eq!(screen.borrow().cursor(), (0, 9));
+
+        editor_type(&mut cfg, &screen, "\x1b[H\x1b[Cx");
+        let drawn = (screen.borrow().snapshot(), screen.borrow().cursor());
+        assert_eq!(screen.borrow().line(0), "日x本語abc");
+        assert_eq!(drawn.1, (0, 3));
+        assert_eq!(drawn, redrawn(&mut cfg, &screen));
+    }
+
+    #[test]
+    fn resize_lays_the_screen_out_again() {
+        let (mut cfg, screen) = editor_on_screen(6, 40);
+        editor_type(&mut cfg, &screen, "a long line that will not fit");
+
+        screen.borrow_mut().resize(4, 10);
+        editor_handle_resize(&mut cfg);
+        editor_type(&mut cfg, &screen, "");
+
+        let screen = screen.borrow();
+        assert_eq!(screen.snapshot(), "l not fit\n~\n[No Name]\n");
+        assert_eq!(screen.cursor(), (0, 9));
+    }
+
+    #[test]
+    fn quitting_returns_and_leaves_the_rest_of_the_input() {
+        let (mut cfg, screen) = editor_on_screen(6, 40);
+        editor_type(&mut cfg, &screen, "\x11abc");
+        editor_restore_terminal(&mut cfg).unwrap();
+
+        assert_eq!(cfg.buffer.len(), 0);
+        let screen = screen.borrow();
+        assert!(!screen.is_raw() && !screen.is_alternate_screen());
+    }
+
+    #[test]
+    fn decodes_key_sequences() {
+        let ctrl = KeyModifier::Ctrl as u8;
+        let modified = |mods, key| EditorKey::Modified(mods, Box::new(key));
+        let cases = [
+            ("1;5", b'C', modified(ctrl, EditorKey::ArrowRight)),
+            ("15", b'~', EditorKey::Function(5)),
+            ("", b'Z', modified(KeyModifier::Shift as u8, EditorKey::Tab)),
+            ("<0;3;4", b'M', EditorKey::MousePress(3, 2)),
+            ("<0;3;4", b'm', EditorKey::MouseRelease(3, 2)),
+            ("<32;3;4", b'M', EditorKey::MouseDrag(3, 2)),
+            ("<65;1;1", b'M', EditorKey::WheelDown),
+            ("105;5", b'u', EditorKey::Ctrl(ctrl_key('i'))),
+            ("9", b'u', EditorKey::Tab),
+            ("27", b'u', EditorKey::EscapeSeq),
+            ("57376", b'u', EditorKey::Unknown),
+            ("99", b'~', EditorKey::Unknown),
+            ("", b'I', EditorKey::Unknown),
+            ("<66;1;1", b'M', EditorKey::Unknown),
+        ];
+        for (params, last, key) in cases {
+            assert_eq!(
+                editor_decode_csi(params, last),
+                key,
+                "ESC [ {}{}",
+                params,
+                last as char
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_ss3_keys() {
+        assert_eq!(editor_csi_letter_key(b'P', 0), EditorKey::Function(1));
+        assert_eq!(editor_csi_letter_key(b'H', 0), EditorKey::HomeKey);
+        assert_eq!(editor_csi_letter_key(b'x', 0), EditorKey::Unknown);
+    }
+
+    #[test]
+    fn find_moves_on_past_matches_it_cannot_show() {
+        let dir = scratch_dir("find-mapped");
+        let path = dir.join("big.txt");
+        let long = "a".repeat(2 << 20);
+        fs::write(&path, format!("{0}zz\nb\n{0}zz\n", long)).unwrap();
+
+        let (mut cfg, _screen) = editor_on_screen(6, 40);
+        cfg.view_threshold = 0;
+        editor_open(&mut cfg, path.to_str().unwrap()).unwrap();
+        while !cfg.buffer.is_complete() {
+            std::thread::yield_now();
+        }
+        editor_update_rows(&mut cfg);
+
+        editor_find_callback(&mut cfg, "zz", EditorKey::Char('z'));
+        assert_eq!((cfg.cy, cfg.cx), (0, 0));
+        editor_find_callback(&mut cfg, "zz", EditorKey::ArrowDown);
+        assert_eq!((cfg.cy, cfg.cx), (2, 0));
+        editor_find_callback(&mut cfg, "zz", EditorKey::ArrowDown);
+        assert_eq!(cfg.cy, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_never_restores_a_stale_highlight() {
+        let (mut cfg, screen) = editor_on_screen(6, 40);
+        editor_type(&mut cfg, &screen, "foo\x06foo\rxxxxxxx\x06f\r");
+        assert_eq!(screen.borrow().line(0), "fooxxxxxxx");
+    }
+
+    #[test]
+    fn drawing_changes_matches_drawing_everything() {
+        let (mut cfg, screen) = editor_on_screen(8, 60);
+        let lines: String = (0..40).map(|i| format!("line {}\r", i)).collect();
+        editor_type(&mut cfg, &screen, &lines);
+
+        for keys in &[
+            "\x1b[5~",
+            "\x1b[<64;1;1M\x1b[<64;1;1M",
+            "\x1b[<65;1;1M",
+            "\x1bh",
+            "\x1b[5~\x1b[<65;1;1M",
+            "\x1bh\x1b[<64;1;1M",
+        ] {
+            editor_type(&mut cfg, &screen, keys);
+            let drawn = (screen.borrow().snapshot(), screen.borrow().cursor());
+            assert_eq!(drawn, redrawn(&mut cfg, &screen), "after {:?}", keys);
+        }
+    }
+
+    #[test]
+    fn diff_lines_shows_the_changed_middle() {
+        assert_eq!(
+            diff_lines("a\nb\nc\n", "a\nb\nc\n"),
+            "The swap file has no changes"
+        );
+        assert_eq!(
+            diff_lines("a\nb\nc\nd\n", "a\nx\ny\nd\n"),
+            "@@ -2,2 +2,2 @@\n-b\n-c\n+x\n+y\n"
+        );
+        assert_eq!(diff_lines("a\nb\n", "a\nb\nc\n"), "@@ -3,0 +3,1 @@\n+c\n");
+        assert_eq!(diff_lines("a\nb\n", "b\n"), "@@ -1,1 +1,0 @@\n-a\n");
+        assert_eq!(diff_lines("", "a\n"), "@@ -1,0 +1,1 @@\n+a\n");
+    }
+
+    #[test]
+    fn parses_the_swap_header() {
+        let content = format!("{}1234\nhello\nworld\n", KILO_SWAP_HEADER);
+        assert_eq!(parse_swap(&content), (Some(1234), "hello\nworld\n"));
+        let content = format!("{}none\nhello\n", KILO_SWAP_HEADER);
+        assert_eq!(parse_swap(&content), (None, "hello\n"));
+        assert_eq!(parse_swap("not a swap file"), (None, "not a swap file"));
+    }
+
+    #[test]
+    fn swap_is_written_in_the_background_once_per_change() {
+        let dir = scratch_dir("swap");
+        let path = dir.join("notes.txt");
+        let (mut cfg, screen) = editor_on_screen(6, 40);
+        cfg.filename = Some(path.to_str().unwrap().to_string());
+        editor_type(&mut cfg, &screen, "hello");
+
+        editor_write_swap(&mut cfg);
+        editor_wait_swap(&mut cfg);
+        let swap = swap_path(cfg.filename.as_ref().unwrap());
+        let content = fs::read_to_string(&swap).unwrap();
+        let pid = std::process::id() as i32;
+        assert_eq!(parse_swap(&content), (Some(pid), "hello\n"));
+        assert_eq!(fs::metadata(&swap).unwrap().mode() & 0o777, 0o600);
+
+        // Nothing changed, so nothing is written.
+        fs::remove_file(&swap).unwrap();
+        editor_write_swap(&mut cfg);
+        assert!(cfg.swap_writer.is_none());
+
+        editor_type(&mut cfg, &screen, "!");
+        editor_write_swap(&mut cfg);
+        editor_remove_swap(&mut cfg);
+        assert!(cfg.swap_enabled);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save_replaces_the_file_it_is_for() {
+        let dir = scratch_dir("save");
+        let (mut cfg, _screen) = editor_on_screen(6, 40);
+        editor_load(&mut cfg, b"short");
+        let mode = |path: &Path| fs::symlink_metadata(path).unwrap().mode() & 0o777;
+
+        // Shorter text truncates, the mode stays.
+        let file = dir.join("file.txt");
+        fs::write(&file, "a much longer original text\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        assert_eq!(editor_write_file(&cfg, file.to_str().unwrap()).unwrap(), 6);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "short\n");
+        assert_eq!(mode(&file), 0o640);
+
+        // The file a link points to is written, the link stays.
+        let link = dir.join("link.txt");
+        unix_fs::symlink("file.txt", &link).unwrap();
+        editor_load(&mut cfg, b"through the link");
+        editor_write_file(&cfg, link.to_str().unwrap()).unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "through the link\n");
+
+        // A new file gets the mode any other new file gets.
+        let usual = dir.join("usual.txt");
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o666)
+            .open(&usual)
+            .unwrap();
+        let new = dir.join("new.txt");
+        editor_write_file(&cfg, new.to_str().unwrap()).unwrap();
+        assert_eq!(mode(&new), mode(&usual));
+
+        // A temporary file left behind is passed over and left alone.
+        let stale = dir.join(format!(".file.txt.kilo-{}.tmp", std::process::id()));
+        fs::write(&stale, "stale").unwrap();
+        editor_load(&mut cfg, b"saved anyway");
+        editor_write_file(&cfg, file.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "saved anyway\n");
+        assert_eq!(fs::read_to_string(&stale).unwrap(), "stale");
+        fs::remove_file(&stale).unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["file.txt", "link.txt", "new.txt", "usual.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,373 @@
+//! Read-only view of files too big to load into the buffer.
+//!
+//! The file is mapped into memory instead of read, so only the pages that
+//! are looked at get loaded. A background thread finds where the lines
+//! start, keeping every `CHECKPOINT`th line start only; any other line is
+//! found by scanning on from the checkpoint before it.
+//!
+//! Nobody may truncate the file meanwhile, reading a page past its new end
+//! kills the editor with SIGBUS.
+
+use nix::sys::mman::{self, MapFlags, ProtFlags};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Lines from one remembered line start to the next.
+const CHECKPOINT: usize = 1024;
+/// How much the indexer scans before it publishes what it found.
+const INDEX_BLOCK: usize = 1 << 20;
+/// Lines are cut off after this many bytes, nobody scrolls that far to the
+/// right anyway.
+const MAX_LINE: usize = 1 << 20;
+/// How much is searched at a time when looking for the last match.
+const RFIND_WINDOW: usize = 1 << 16;
+
+struct Map {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The pages are never written, any thread may read them.
+unsafe impl Send for Map {}
+unsafe impl Sync for Map {}
+
+impl Map {
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = mman::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[derive(Default)]
+struct LineIndex {
+    /// Start of line `i * CHECKPOINT`, for the lines found so far.
+    checkpoints: Vec<usize>,
+    /// Number of lines found so far.
+    lines: usize,
+    done: bool,
+}
+
+#[derive(Clone)]
+pub struct MappedFile {
+    map: Arc<Map>,
+    index: Arc<Mutex<LineIndex>>,
+}
+
+impl MappedFile {
+    /// Map `file` and start looking for its lines in the background.
+    pub fn new(file: &File) -> io::Result<Self> {
+        let len = file.metadata()?.len() as usize;
+        let ptr = unsafe {
+            mman::mmap(
+                ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        }
+        .map_err(io::Error::other)?;
+
+        let map = Arc::new(Map { ptr, len });
+        let index = Arc::new(Mutex::new(LineIndex {
+            checkpoints: vec![0],
+            ..LineIndex::default()
+        }));
+        let (indexer_map, indexer_index) = (map.clone(), index.clone());
+        thread::spawn(move || index_lines(&indexer_map, &indexer_index));
+
+        Ok(MappedFile { map, index })
+    }
+
+    /// Number of lines found so far.
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().lines
+    }
+
+    /// Whether all lines have been found.
+    pub fn is_complete(&self) -> bool {
+        self.index.lock().unwrap().done
+    }
+
+    /// Offset where line `y` starts, for `y` up to the number of lines
+    /// found so far. The line past the last one starts at the end.
+    fn line_start(&self, y: usize) -> usize {
+        let (mut line, mut pos) = {
+            let index = self.index.lock().unwrap();
+            let i = (y / CHECKPOINT).min(index.checkpoints.len() - 1);
+            (i * CHECKPOINT, index.checkpoints[i])
+        };
+        let bytes = self.map.bytes();
+        while line < y {
+            pos += find_byte(&bytes[pos..], b'\n').map_or(bytes.len() - pos, |i| i + 1);
+            line += 1;
+        }
+        pos
+    }
+
+    /// Line containing the byte at `offset`.
+    fn line_at(&self, offset: usize) -> usize {
+        let (y, start) = {
+            let index = self.index.lock().unwrap();
+            let i = index.checkpoints.partition_point(|&start| start <= offset) - 1;
+            (i * CHECKPOINT, index.checkpoints[i])
+        };
+        y + count_byte(&self.map.bytes()[start..offset], b'\n')
+    }
+
+    /// Lines from `y` on that were found so far, without line endings.
+    pub fn lines_from(&self, y: usize) -> impl Iterator<Item = Cow<'_, str>> {
+        let bytes = self.map.bytes();
+        let len = self.len();
+        let mut pos = self.line_start(y.min(len));
+
+        (y..len).map(move |_| {
+            let mut line = &bytes[pos..];
+            match find_byte(line, b'\n') {
+                Some(end) => {
+                    line = &line[..end];
+                    pos += end + 1;
+                    if line.last() == Some(&b'\r') {
+                        line = &line[..end - 1];
+                    }
+                }
+                None => pos = bytes.len(),
+            }
+            String::from_utf8_lossy(&line[..line.len().min(MAX_LINE)])
+        })
+    }
+
+    /// First line containing `query`, looking at line `start` first and
+    /// moving on forward or backward, wrapping around at either end.
+    ///
+    /// Only the lines found so far are searched.
+    pub fn find_line(&self, query: &str, start: usize, forward: bool) -> Option<usize> {
+        let len = self.len();
+        if start >= len {
+            return None;
+        }
+        if query.is_empty() {
+            return Some(start);
+        }
+        let bytes = self.map.bytes();
+        let end = self.line_start(len);
+        let query = query.as_bytes();
+
+        let found = if forward {
+            let from = self.line_start(start);
+            find(&bytes[from..end], query)
+                .map(|i| from + i)
+                .or_else(|| find(&bytes[..from], query))
+        } else {
+            let to = self.line_start(start + 1);
+            rfind(&bytes[..to], query).or_else(|| rfind(&bytes[to..end], query).map(|i| to + i))
+        };
+        found.map(|offset| self.line_at(offset))
+    }
+
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.map.bytes())
+    }
+}
+
+/// Record where lines start, publishing progress every `INDEX_BLOCK` bytes.
+///
+/// Gives up once nobody is interested in the index anymore.
+fn index_lines(map: &Map, index: &Arc<Mutex<LineIndex>>) {
+    let bytes = map.bytes();
+    let mut lines = 0;
+    let mut checkpoints = Vec::new();
+    let mut pos = 0;
+
+    for block in bytes.chunks(INDEX_BLOCK) {
+        if Arc::strong_count(index) == 1 {
+            return;
+        }
+        let block_start = pos;
+        while let Some(i) = find_byte(&bytes[pos..block_start + block.len()], b'\n') {
+            pos += i + 1;
+            lines += 1;
+            if lines % CHECKPOINT == 0 {
+                checkpoints.push(pos);
+            }
+        }
+        pos = block_start + block.len();
+
+        let mut index = index.lock().unwrap();
+        index.checkpoints.append(&mut checkpoints);
+        index.lines = lines;
+    }
+
+    let mut index = index.lock().unwrap();
+    if bytes.last().is_some_and(|&b| b != b'\n') {
+        index.lines += 1;
+    }
+    index.done = true;
+}
+
+fn find_byte(haystack: &[u8], byte: u8) -> Option<usize> {
+    let found = unsafe {
+        libc::memchr(
+            haystack.as_ptr() as *const libc::c_void,
+            byte as libc::c_int,
+            haystack.len(),
+        )
+    };
+    if found.is_null() {
+        None
+    } else {
+        Some(found as usize - haystack.as_ptr() as usize)
+    }
+}
+
+fn count_byte(haystack: &[u8], byte: u8) -> usize {
+    haystack.iter().filter(|&&b| b == byte).count()
+}
+
+/// Offset of the first `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let found = unsafe {
+        libc::memmem(
+            haystack.as_ptr() as *const libc::c_void,
+            haystack.len(),
+            needle.as_ptr() as *const libc::c_void,
+            needle.len(),
+        )
+    };
+    if found.is_null() {
+        None
+    } else {
+        Some(found as usize - haystack.as_ptr() as usize)
+    }
+}
+
+/// Offset of the last `needle` in `haystack`, searching a window at a time
+/// from the end.
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    // Each window must reach past the overlap with the one after it.
+    let window = RFIND_WINDOW.max(2 * needle.len());
+    let mut end = haystack.len();
+    loop {
+        let start = end.saturating_sub(window);
+        let window = &haystack[start..end];
+        let mut last = None;
+        let mut pos = 0;
+        while let Some(i) = find(&window[pos..], needle) {
+            last = Some(start + pos + i);
+            pos += i + 1;
+        }
+        if last.is_some() || start == 0 {
+            return last;
+        }
+        // Overlap the windows so a match across the boundary isn't missed.
+        end = (start + needle.len() - 1).min(haystack.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Map a file holding `text`, once all of its lines are found.
+    fn mapped(text: &[u8]) -> MappedFile {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let n = FILES.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("kilo-mapped-{}-{}", process::id(), n));
+        fs::write(&path, text).unwrap();
+        let file = MappedFile::new(&File::open(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        while !file.is_complete() {
+            thread::yield_now();
+        }
+        file
+    }
+
+    fn lines(file: &MappedFile) -> Vec<String> {
+        file.lines_from(0).map(Cow::into_owned).collect()
+    }
+
+    #[test]
+    fn finds_lines_with_or_without_final_newline() {
+        assert_eq!(lines(&mapped(b"a\nb\nc")), ["a", "b", "c"]);
+        assert_eq!(lines(&mapped(b"a\nb\n")), ["a", "b"]);
+        assert_eq!(lines(&mapped(b"\n\n")), ["", ""]);
+    }
+
+    #[test]
+    fn strips_crlf_line_endings() {
+        let file = mapped(b"a\r\nb\r\n\r\nc\r");
+        assert_eq!(lines(&file), ["a", "b", "", "c\r"]);
+    }
+
+    #[test]
+    fn finds_lines_across_checkpoints() {
+        let text: String = (0..3000).map(|i| format!("line {}\n", i)).collect();
+        let file = mapped(text.as_bytes());
+        assert_eq!(file.len(), 3000);
+
+        let mut starts = vec![0];
+        for (i, b) in text.bytes().enumerate() {
+            if b == b'\n' {
+                starts.push(i + 1);
+            }
+        }
+        for &y in &[0, 1, 1023, 1024, 1025, 2047, 2048, 2999, 3000] {
+            assert_eq!(file.line_start(y), starts[y], "start of line {}", y);
+        }
+        for &y in &[0, 1023, 1024, 2048, 2999] {
+            assert_eq!(file.line_at(starts[y]), y);
+            assert_eq!(file.line_at(starts[y + 1] - 1), y);
+        }
+        let around: Vec<_> = file.lines_from(1023).take(3).collect();
+        assert_eq!(around, ["line 1023", "line 1024", "line 1025"]);
+    }
+
+    #[test]
+    fn find_line_wraps_around_both_ways() {
+        let file = mapped(b"apple\nbanana\ncherry\napple pie\n");
+        assert_eq!(file.find_line("apple", 1, true), Some(3));
+        assert_eq!(file.find_line("banana", 2, true), Some(1));
+        assert_eq!(file.find_line("apple", 2, false), Some(0));
+        assert_eq!(file.find_line("cherry", 1, false), Some(2));
+        assert_eq!(file.find_line("apple", 3, false), Some(3));
+        assert_eq!(file.find_line("durian", 0, true), None);
+        assert_eq!(file.find_line("durian", 0, false), None);
+        assert_eq!(file.find_line("apple", 4, true), None);
+    }
+
+    #[test]
+    fn rfind_handles_needles_longer_than_a_window() {
+        let text: String = (0..40_000).map(|i| format!("{} ", i)).collect();
+        let file = mapped(format!("{}\n", text).as_bytes());
+        let long = &text[100_000..170_000];
+        assert_eq!(file.find_line(long, 0, false), Some(0));
+        let missing = format!("{}y", long);
+        assert_eq!(file.find_line(&missing, 0, false), None);
+
+        assert_eq!(rfind(b"ab", b"abc"), None);
+        let mut haystack = vec![b'a'; 3 * RFIND_WINDOW];
+        haystack[10..10 + RFIND_WINDOW].copy_from_slice(&[b'b'; RFIND_WINDOW]);
+        assert_eq!(rfind(&haystack, &[b'b'; RFIND_WINDOW]), Some(10));
+    }
+}
@@ -0,0 +1,250 @@
+//! Frame buffers for differential rendering.
+//!
+//! The editor draws every refresh into a back `Frame` of styled cells.
+//! Comparing it with the front `Frame`, which mirrors what the terminal
+//! currently shows, lets us write only the cells that actually changed.
+
+use crate::theme::{ColorSupport, Style};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct Cell {
+    /// Grapheme shown in the cell, empty for the right half of a wide one.
+    pub text: String,
+    pub style: Style,
+}
+
+pub struct Frame {
+    rows: usize,
+    cols: usize,
+    /// Style of blank cells, what `\x1b[K` is expected to paint.
+    blank: Style,
+    /// A line shorter than `cols` is unknown and always redrawn in full.
+    lines: Vec<Vec<Cell>>,
+}
+
+impl Frame {
+    /// A frame whose content is unknown, as after clearing the screen.
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Frame {
+            rows,
+            cols,
+            blank: Style::default(),
+            lines: vec![Vec::new(); rows],
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    /// Blank every cell using `style`.
+    pub fn clear(&mut self, style: Style) {
+        let blank = Cell {
+            text: " ".to_string(),
+            style,
+        };
+        self.blank = style;
+        for line in self.lines.iter_mut() {
+            line.clear();
+            line.resize(self.cols, blank.clone());
+        }
+    }
+
+    /// Put grapheme `g`, `width` columns wide, at column `x` of line `y`.
+    ///
+    /// Zero width graphemes are attached to the cell before them.
+    pub fn put(&mut self, y: usize, x: usize, g: &str, width: usize, style: Style) {
+        if y >= self.rows {
+            return;
+        }
+        let line = &mut self.lines[y];
+
+        if width == 0 {
+            if let Some(cell) = x.checked_sub(1).and_then(|x| line.get_mut(x)) {
+                cell.text.push_str(g);
+            }
+            return;
+        }
+        if x + width > self.cols {
+            return;
+        }
+
+        line[x] = Cell {
+            text: g.to_string(),
+            style,
+        };
+        for cell in line[x + 1..x + width].iter_mut() {
+            *cell = Cell {
+                text: String::new(),
+                style,
+            };
+        }
+    }
+
+    /// Print `text` from column `x` of line `y`, clipped to the frame.
+    ///
+    /// Returns the column after the last grapheme.
+    pub fn print(&mut self, y: usize, mut x: usize, text: &str, style: Style) -> usize {
+        for g in text.graphemes(true) {
+            let width = g.width();
+            if x + width > self.cols {
+                break;
+            }
+            self.put(y, x, g, width, style);
+            x += width;
+        }
+
+        x
+    }
+
+    /// Move lines `top..bottom` up by `n` lines, or down when `n` is
+    /// negative, the way the terminal scrolls a region.
+    ///
+    /// Lines scrolled in are unknown.
+    pub fn scroll(&mut self, top: usize, bottom: usize, n: isize) {
+        let region = &mut self.lines[top..bottom];
+        let len = region.len();
+        let shift = n.unsigned_abs().min(len);
+        if n > 0 {
+            region.rotate_left(shift);
+            for line in region[len - shift..].iter_mut() {
+                line.clear();
+            }
+        } else {
+            region.rotate_right(shift);
+            for line in region[..shift].iter_mut() {
+                line.clear();
+            }
+        }
+    }
+
+    /// Append to `out` what turns the screen showing `self` into `back`.
+    pub fn diff(&self, back: &Frame, support: ColorSupport, out: &mut String) {
+        let mut curr_style = None;
+
+        for (y, (front, line)) in self.lines.iter().zip(back.lines.iter()).enumerate() {
+            if front == line {
+                continue;
+            }
+
+            let (mut start, mut end) = if front.len() != line.len() {
+                (0, line.len())
+            } else {
+                let start = front.iter().zip(line).position(|(a, b)| a != b).unwrap();
+                let end = front.iter().zip(line).rposition(|(a, b)| a != b).unwrap() + 1;
+                (start, end)
+            };
+
+            // Never write half of a wide character, on screen or in the frame.
+            let is_half = |x: usize| {
+                line[x].text.is_empty() || front.get(x).is_some_and(|c| c.text.is_empty())
+            };
+            while start > 0 && is_half(start) {
+                start -= 1;
+            }
+            while end < line.len() && is_half(end) {
+                end += 1;
+            }
+
+            // Trailing blanks are cheaper to erase than to write.
+            let blank_from = line
+                .iter()
+                .rposition(|c| c.text != " " || c.style != back.blank)
+                .map_or(0, |x| x + 1);
+            let erase = end > blank_from;
+            if erase {
+                end = blank_from.max(start);
+            }
+
+            out.push_str(&format!("\x1b[{};{}H", y + 1, start + 1));
+            for cell in &line[start..end] {
+                if cell.text.is_empty() {
+                    continue;
+                }
+                if curr_style != Some(cell.style) {
+                    out.push_str(&cell.style.sgr(support));
+                    curr_style = Some(cell.style);
+                }
+                out.push_str(&cell.text);
+            }
+            if erase {
+                if curr_style != Some(back.blank) {
+                    out.push_str(&back.blank.sgr(support));
+                    curr_style = Some(back.blank);
+                }
+                out.push_str("\x1b[K");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame of `lines`, 8 columns wide, in the default style.
+    fn frame(lines: &[&str]) -> Frame {
+        let mut frame = Frame::new(lines.len(), 8);
+        frame.clear(Style::default());
+        for (y, line) in lines.iter().enumerate() {
+            frame.print(y, 0, line, Style::default());
+        }
+        frame
+    }
+
+    fn diff(front: &Frame, back: &Frame) -> String {
+        let mut out = String::new();
+        front.diff(back, ColorSupport::TrueColor, &mut out);
+        out
+    }
+
+    #[test]
+    fn identical_frames_need_no_output() {
+        let front = frame(&["hello", "日本語"]);
+        assert_eq!(diff(&front, &frame(&["hello", "日本語"])), "");
+    }
+
+    #[test]
+    fn changed_cell_is_written_alone() {
+        let front = frame(&["hello", "world"]);
+        let back = frame(&["hello", "wOrld"]);
+        assert_eq!(diff(&front, &back), "\x1b[2;2H\x1b[0mO");
+    }
+
+    #[test]
+    fn wide_char_is_written_whole() {
+        let mut front = frame(&["a日b"]);
+        // The right half of 日 was overwritten on screen.
+        front.lines[0][2].text = " ".to_string();
+        assert_eq!(diff(&front, &frame(&["a日b"])), "\x1b[1;2H\x1b[0m日");
+
+        let back = frame(&["a本b"]);
+        assert_eq!(diff(&frame(&["a日b"]), &back), "\x1b[1;2H\x1b[0m本");
+    }
+
+    #[test]
+    fn trailing_blanks_are_erased() {
+        let front = frame(&["abcdef"]);
+        assert_eq!(diff(&front, &frame(&["ab"])), "\x1b[1;3H\x1b[0m\x1b[K");
+        assert_eq!(diff(&front, &frame(&["abX"])), "\x1b[1;3H\x1b[0mX\x1b[K");
+    }
+
+    #[test]
+    fn lines_scrolled_in_are_drawn_in_full() {
+        let mut front = frame(&["a", "b", "c"]);
+        front.scroll(0, 3, 1);
+        assert_eq!(
+            diff(&front, &frame(&["b", "c", "d"])),
+            "\x1b[3;1H\x1b[0md\x1b[K"
+        );
+
+        let mut front = frame(&["a", "b", "c"]);
+        front.scroll(1, 3, -1);
+        assert_eq!(
+            diff(&front, &frame(&["a", "x", "b"])),
+            "\x1b[2;1H\x1b[0mx\x1b[K"
+        );
+    }
+}
@@ -0,0 +1,588 @@
+//! Terminal backends.
+//!
+//! The editor only talks to the terminal through the `Terminal` trait, so
+//! the real TTY can be swapped for `VirtualScreen` when driving kilo from
+//! scripted key sequences in tests.
+
+use nix::sys::signal::{self, Signal};
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic;
+#[cfg(test)]
+use std::rc::Rc;
+use std::sync::Mutex;
+use termios::*;
+#[cfg(test)]
+use unicode_width::UnicodeWidthChar;
+
+/// Written once raw mode is on: switch to the alternate screen, turn on
+/// bracketed paste and SGR mouse reporting of clicks and drags, and save
+/// the window title on the title stack.
+pub const ENTER_SCREEN: &[u8] = b"\x1b[?1049h\x1b[?2004h\x1b[?1002h\x1b[?1006h\x1b[22;0t";
+
+/// Undoes `ENTER_SCREEN` and makes sure the cursor is visible again.
+pub const LEAVE_SCREEN: &[u8] =
+    b"\x1b[0m\x1b[23;0t\x1b[?1006l\x1b[?1002l\x1b[?2004l\x1b[?25h\x1b[?1049l";
+
+/// Ask for the kitty keyboard protocol flags, then for the primary device
+/// attributes.
+pub const KITTY_KEYBOARD_QUERY: &[u8] = b"\x1b[?u\x1b[c";
+
+/// Push the kitty keyboard flags that disambiguate escape codes.
+pub const KITTY_KEYBOARD_PUSH: &[u8] = b"\x1b[>1u";
+
+/// Pop what `KITTY_KEYBOARD_PUSH` pushed.
+pub const KITTY_KEYBOARD_POP: &[u8] = b"\x1b[<u";
+
+/// Original settings of the TTY, kept where the panic hook can reach them.
+static SAVED_TERMIOS: Mutex<Option<(RawFd, Termios)>> = Mutex::new(None);
+
+pub trait Terminal {
+    /// Read a single byte of input.
+    ///
+    /// Returns `Ok(None)` when nothing arrived before the read timeout, and
+    /// an `UnexpectedEof` error once no more input will ever come.
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+
+    /// Write a complete frame (text and escape sequences) to the screen.
+    fn write_frame(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Size of the screen as `(rows, cols)`.
+    fn size(&mut self) -> Option<(usize, usize)>;
+
+    fn enable_raw_mode(&mut self) -> io::Result<()>;
+
+    fn disable_raw_mode(&mut self) -> io::Result<()>;
+
+    /// Hand the terminal back to the shell until we are continued.
+    fn suspend(&mut self) -> io::Result<()>;
+
+    /// File descriptor to wait on for input.
+    ///
+    /// Without one, input is taken to be always ready.
+    fn input_fd(&self) -> Option<RawFd>;
+}
+
+// *** TTY ***
+
+/// The controlling terminal.
+///
+/// It is opened through `/dev/tty` rather than stdin/stdout, so the document
+/// can be piped in on stdin while keys still come from the keyboard.
+pub struct TtyTerminal {
+    tty: File,
+    fd: RawFd,
+    orig: Termios,
+}
+
+impl TtyTerminal {
+    pub fn new() -> io::Result<Self> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        let fd = tty.as_raw_fd();
+        let mut orig = Termios::from_fd(fd)?;
+        tcgetattr(fd, &mut orig)?;
+        *SAVED_TERMIOS.lock().unwrap() = Some((fd, orig));
+
+        Ok(TtyTerminal { tty, fd, orig })
+    }
+
+    fn cursor_position(&mut self) -> Option<(usize, usize)> {
+        self.write_frame(b"\x1b[6n").ok()?;
+
+        let mut buf = Vec::new();
+        while buf.len() < 31 {
+            match self.read_byte() {
+                Ok(Some(b'R')) => break,
+                Ok(Some(b)) => buf.push(b),
+                _ => return None,
+            }
+        }
+
+        if !buf.starts_with(b"\x1b[") {
+            return None;
+        }
+        let report = String::from_utf8_lossy(&buf[2..]).to_string();
+        let mut parts = report.split(';');
+        let rows = parts.next()?.parse().ok()?;
+        let cols = parts.next()?.parse().ok()?;
+        Some((rows, cols))
+    }
+}
+
+impl Terminal for TtyTerminal {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut c = [0_u8; 1];
+        match nix::unistd::read(self.fd, &mut c) {
+            Ok(1) => Ok(Some(c[0])),
+            Ok(_) => Ok(None),
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => Ok(None),
+            Err(nix::Error::Sys(errno)) => Err(io::Error::from_raw_os_error(errno as i32)),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.tty.write_all(buf)?;
+        self.tty.flush()
+    }
+
+    fn size(&mut self) -> Option<(usize, usize)> {
+        let mut winsize = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        unsafe {
+            if libc::ioctl(self.fd, libc::TIOCGWINSZ, &mut winsize) == -1 || winsize.ws_col == 0 {
+                self.write_frame(b"\x1b[999C\x1b[999B").ok()?;
+                return self.cursor_position();
+            }
+        }
+
+        Some((winsize.ws_row as usize, winsize.ws_col as usize))
+    }
+
+    fn enable_raw_mode(&mut self) -> io::Result<()> {
+        let mut raw = self.orig;
+        raw.c_iflag &= !(BRKINT | INPCK | ISTRIP | ICRNL | IXON);
+        raw.c_oflag &= !(OPOST);
+        raw.c_cflag |= CS8;
+        raw.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
+        raw.c_cc[VMIN] = 0;
+        raw.c_cc[VTIME] = 1;
+        tcsetattr(self.fd, TCSAFLUSH, &raw)
+    }
+
+    fn disable_raw_mode(&mut self) -> io::Result<()> {
+        tcsetattr(self.fd, TCSAFLUSH, &self.orig)
+    }
+
+    fn suspend(&mut self) -> io::Result<()> {
+        signal::raise(Signal::SIGTSTP).map_err(io::Error::other)
+    }
+
+    fn input_fd(&self) -> Option<RawFd> {
+        Some(self.fd)
+    }
+}
+
+/// Put the TTY back the way we found it before the panic message is printed.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_saved_termios();
+        default_hook(info);
+    }));
+}
+
+fn restore_saved_termios() {
+    let saved = match SAVED_TERMIOS.lock() {
+        Ok(saved) => *saved,
+        Err(poisoned) => *poisoned.into_inner(),
+    };
+    if let Some((fd, orig)) = saved {
+        let _ = nix::unistd::write(fd, LEAVE_SCREEN);
+        let _ = tcsetattr(fd, TCSAFLUSH, &orig);
+    }
+}
+
+// *** Virtual screen ***
+
+/// Headless in-memory terminal.
+///
+/// Keys queued with `feed_keys` are handed out by `read_byte`, and every
+/// frame the editor writes is interpreted into a grid of cells that can be
+/// compared against an expected snapshot. Once the keys run out, reading
+/// fails with `UnexpectedEof`.
+///
+/// Share it as an `Rc<RefCell<VirtualScreen>>` to look at the screen while
+/// the editor owns the terminal.
+#[cfg(test)]
+pub struct VirtualScreen {
+    /// A wide char takes its cell and the one after it, which holds
+    /// `WIDE_TAIL`.
+    rows: usize,
+    cols: usize,
+    cells: Vec<Vec<char>>,
+    primary: Option<Vec<Vec<char>>>,
+    cx: usize,
+    cy: usize,
+    cursor_visible: bool,
+    title: String,
+    raw: bool,
+    /// Queued input, `None` where the read times out.
+    input: VecDeque<Option<u8>>,
+    pending: Vec<u8>,
+    /// Scroll region as a `[top, bottom)` range of lines.
+    region: (usize, usize),
+}
+
+/// Right half of a wide char.
+#[cfg(test)]
+const WIDE_TAIL: char = '\0';
+
+#[cfg(test)]
+impl VirtualScreen {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        VirtualScreen {
+            rows,
+            cols,
+            cells: vec![vec![' '; cols]; rows],
+            primary: None,
+            cx: 0,
+            cy: 0,
+            cursor_visible: true,
+            title: String::new(),
+            raw: false,
+            input: VecDeque::new(),
+            pending: Vec::new(),
+            region: (0, rows),
+        }
+    }
+
+    /// Queue raw bytes as if they were typed on the keyboard in one go.
+    ///
+    /// A read timeout follows them, so a lone ESC at the end is the Escape
+    /// key.
+    pub fn feed_keys(&mut self, keys: &[u8]) {
+        self.input.extend(keys.iter().copied().map(Some));
+        self.input.push_back(None);
+    }
+
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        self.cells.resize(rows, vec![' '; cols]);
+        for line in self.cells.iter_mut() {
+            line.resize(cols, ' ');
+            // A wide char cut in half at the edge is gone.
+            if let Some(last) = line.last_mut().filter(|c| c.width() == Some(2)) {
+                *last = ' ';
+            }
+        }
+        self.rows = rows;
+        self.cols = cols;
+        self.region = (0, rows);
+        self.cx = self.cx.min(cols.saturating_sub(1));
+        self.cy = self.cy.min(rows.saturating_sub(1));
+    }
+
+    /// Text of screen line `y` with trailing blanks removed.
+    pub fn line(&self, y: usize) -> String {
+        let line: String = self.cells[y].iter().filter(|&&c| c != WIDE_TAIL).collect();
+        line.trim_end().to_string()
+    }
+
+    /// Whole screen, one line per row.
+    pub fn snapshot(&self) -> String {
+        (0..self.rows)
+            .map(|y| self.line(y))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Cursor position as `(row, col)`, zero based.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.cy, self.cx)
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn is_raw(&self) -> bool {
+        self.raw
+    }
+
+    pub fn is_alternate_screen(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    /// Window title last set with OSC 0 or 2.
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Write `c` at the cursor. Zero width chars are dropped, nothing the
+    /// editor draws combines with the char before it.
+    fn put_char(&mut self, c: char) {
+        let width = match c.width() {
+            Some(0) | None => return,
+            Some(width) => width,
+        };
+        if self.cx + width > self.cols {
+            self.cx = 0;
+            self.line_feed();
+        }
+        if self.cy < self.rows && self.cx + width <= self.cols {
+            for x in self.cx..self.cx + width {
+                self.split_wide(x);
+            }
+            let line = &mut self.cells[self.cy];
+            line[self.cx] = c;
+            if width == 2 {
+                line[self.cx + 1] = WIDE_TAIL;
+            }
+        }
+        self.cx += width;
+    }
+
+    /// Blank out the other half of a wide char with a half at `x` on the
+    /// cursor line, as `x` is about to be overwritten.
+    fn split_wide(&mut self, x: usize) {
+        let line = &mut self.cells[self.cy];
+        if line[x] == WIDE_TAIL && x > 0 {
+            line[x - 1] = ' ';
+        }
+        if line.get(x + 1) == Some(&WIDE_TAIL) {
+            line[x + 1] = ' ';
+        }
+    }
+
+    fn line_feed(&mut self) {
+        if self.cy + 1 == self.region.1 {
+            self.scroll_up(1);
+        } else if self.cy + 1 < self.rows {
+            self.cy += 1;
+        }
+    }
+
+    /// Scroll the lines of the scroll region up by `n`.
+    fn scroll_up(&mut self, n: usize) {
+        let (top, bottom) = self.region;
+        for _ in 0..n.min(bottom - top) {
+            self.cells.remove(top);
+            self.cells.insert(bottom - 1, vec![' '; self.cols]);
+        }
+    }
+
+    /// Scroll the lines of the scroll region down by `n`.
+    fn scroll_down(&mut self, n: usize) {
+        let (top, bottom) = self.region;
+        for _ in 0..n.min(bottom - top) {
+            self.cells.remove(bottom - 1);
+            self.cells.insert(top, vec![' '; self.cols]);
+        }
+    }
+
+    fn erase_line_from(&mut self, x: usize) {
+        if self.cy < self.rows && x < self.cols {
+            self.split_wide(x);
+            for cell in self.cells[self.cy].iter_mut().skip(x) {
+                *cell = ' ';
+            }
+        }
+    }
+
+    fn erase_display(&mut self) {
+        for line in self.cells.iter_mut() {
+            for cell in line.iter_mut() {
+                *cell = ' ';
+            }
+        }
+    }
+
+    /// Apply a CSI sequence, `params` excludes the introducer and final byte.
+    fn csi(&mut self, params: &str, final_byte: u8) {
+        let private = params.starts_with('?');
+        let nums: Vec<usize> = params
+            .trim_start_matches('?')
+            .split(';')
+            .map(|n| n.parse().unwrap_or(0))
+            .collect();
+        let arg = |i: usize, default: usize| match nums.get(i) {
+            Some(&0) | None => default,
+            Some(&n) => n,
+        };
+
+        match final_byte {
+            b'H' | b'f' => {
+                self.cy = (arg(0, 1) - 1).min(self.rows.saturating_sub(1));
+                self.cx = (arg(1, 1) - 1).min(self.cols.saturating_sub(1));
+            }
+            b'A' => self.cy = self.cy.saturating_sub(arg(0, 1)),
+            b'B' => self.cy = (self.cy + arg(0, 1)).min(self.rows.saturating_sub(1)),
+            b'C' => self.cx = (self.cx + arg(0, 1)).min(self.cols.saturating_sub(1)),
+            b'D' => self.cx = self.cx.saturating_sub(arg(0, 1)),
+            b'J' if nums[0] == 2 => self.erase_display(),
+            b'K' => self.erase_line_from(self.cx),
+            b'S' => self.scroll_up(arg(0, 1)),
+            b'T' => self.scroll_down(arg(0, 1)),
+            b'r' if !private => {
+                let top = arg(0, 1) - 1;
+                let bottom = arg(1, self.rows).min(self.rows);
+                if top < bottom {
+                    self.region = (top, bottom);
+                    self.cx = 0;
+                    self.cy = 0;
+                }
+            }
+            b'c' if params.is_empty() || params == "0" => {
+                self.input.extend(b"\x1b[?62;22c".iter().copied().map(Some));
+            }
+            b'n' if nums[0] == 6 => {
+                let report = format!("\x1b[{};{}R", self.cy + 1, self.cx + 1);
+                self.input.extend(report.bytes().map(Some));
+            }
+            b'h' | b'l' if private && nums[0] == 25 => {
+                self.cursor_visible = final_byte == b'h';
+            }
+            b'h' if private && nums[0] == 1049 && self.primary.is_none() => {
+                let blank = vec![vec![' '; self.cols]; self.rows];
+                self.primary = Some(std::mem::replace(&mut self.cells, blank));
+            }
+            b'l' if private && nums[0] == 1049 => {
+                if let Some(primary) = self.primary.take() {
+                    self.cells = primary;
+                    self.resize(self.rows, self.cols);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Interpret as much of the pending output as forms complete tokens.
+    fn interpret(&mut self) {
+        let buf = std::mem::take(&mut self.pending);
+        let mut i = 0;
+
+        while i < buf.len() {
+            match buf[i] {
+                0x1b => {
+                    if i + 1 >= buf.len() {
+                        break;
+                    }
+                    match buf[i + 1] {
+                        b'[' => {
+                            let end = buf[i + 2..].iter().position(|b| (0x40..=0x7e).contains(b));
+                            match end {
+                                Some(end) => {
+                                    let params = String::from_utf8_lossy(&buf[i + 2..i + 2 + end]);
+                                    self.csi(&params, buf[i + 2 + end]);
+                                    i += end + 3;
+                                }
+                                None => break,
+                            }
+                        }
+                        b']' => {
+                            let end = buf[i + 2..].iter().position(|&b| b == 0x07);
+                            match end {
+                                Some(end) => {
+                                    let osc = String::from_utf8_lossy(&buf[i + 2..i + 2 + end]);
+                                    if let Some(title) =
+                                        osc.strip_prefix("0;").or_else(|| osc.strip_prefix("2;"))
+                                    {
+                                        self.title = title.to_string();
+                                    }
+                                    i += end + 3;
+                                }
+                                None => break,
+                            }
+                        }
+                        _ => i += 2,
+                    }
+                }
+                b'\r' => {
+                    self.cx = 0;
+                    i += 1;
+                }
+                b'\n' => {
+                    self.line_feed();
+                    i += 1;
+                }
+                b if b < 0x20 => i += 1,
+                _ => {
+                    let len = match buf[i] {
+                        b if b >= 0xf0 => 4,
+                        b if b >= 0xe0 => 3,
+                        b if b >= 0xc0 => 2,
+                        _ => 1,
+                    };
+                    if i + len > buf.len() {
+                        break;
+                    }
+                    let s = String::from_utf8_lossy(&buf[i..i + len]).to_string();
+                    for c in s.chars() {
+                        self.put_char(c);
+                    }
+                    i += len;
+                }
+            }
+        }
+
+        self.pending = buf[i..].to_vec();
+    }
+}
+
+#[cfg(test)]
+impl Terminal for VirtualScreen {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        self.input
+            .pop_front()
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(buf);
+        self.interpret();
+        Ok(())
+    }
+
+    fn size(&mut self) -> Option<(usize, usize)> {
+        Some((self.rows, self.cols))
+    }
+
+    fn enable_raw_mode(&mut self) -> io::Result<()> {
+        self.raw = true;
+        Ok(())
+    }
+
+    fn disable_raw_mode(&mut self) -> io::Result<()> {
+        self.raw = false;
+        Ok(())
+    }
+
+    fn suspend(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn input_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+#[cfg(test)]
+impl<T: Terminal> Terminal for Rc<RefCell<T>> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        self.borrow_mut().read_byte()
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.borrow_mut().write_frame(buf)
+    }
+
+    fn size(&mut self) -> Option<(usize, usize)> {
+        self.borrow_mut().size()
+    }
+
+    fn enable_raw_mode(&mut self) -> io::Result<()> {
+        self.borrow_mut().enable_raw_mode()
+    }
+
+    fn disable_raw_mode(&mut self) -> io::Result<()> {
+        self.borrow_mut().disable_raw_mode()
+    }
+
+    fn suspend(&mut self) -> io::Result<()> {
+        self.borrow_mut().suspend()
+    }
+
+    fn input_fd(&self) -> Option<RawFd> {
+        self.borrow().input_fd()
+    }
+}
@@ -0,0 +1,382 @@
+//! Color themes.
+//!
+//! A theme maps every `Highlight` class to a full `Style`. Styles are
+//! turned into SGR sequences for whatever the terminal supports, so 24-bit
+//! colors are downgraded to the 256 color palette or to the basic 16.
+
+use crate::Highlight;
+use std::env;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Color {
+    /// One of the 16 basic colors, as configured in the terminal.
+    Ansi(u8),
+    /// Index into the 256 color palette.
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Default)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+/// How many colors the terminal can show, from least to most capable.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ColorSupport {
+    Basic,
+    Indexed,
+    TrueColor,
+}
+
+impl ColorSupport {
+    /// Guess the color support from `COLORTERM` and `TERM`.
+    pub fn detect() -> Self {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorSupport::TrueColor
+        } else if term.contains("256color") {
+            ColorSupport::Indexed
+        } else {
+            ColorSupport::Basic
+        }
+    }
+}
+
+pub struct Theme {
+    pub name: &'static str,
+    pub normal: Style,
+    pub comment: Style,
+    pub keyword1: Style,
+    pub keyword2: Style,
+    pub string: Style,
+    pub number: Style,
+    pub matched: Style,
+    pub control: Style,
+    pub selection: Style,
+    pub status_bar: Style,
+}
+
+impl Theme {
+    pub fn style(&self, hl: Highlight) -> Style {
+        match hl {
+            Highlight::Normal => self.normal,
+            Highlight::Comment | Highlight::MLComment => self.comment,
+            Highlight::Keyword1 => self.keyword1,
+            Highlight::Keyword2 => self.keyword2,
+            Highlight::String => self.string,
+            Highlight::Number => self.number,
+            Highlight::Match => self.matched,
+            Highlight::Control => self.control,
+        }
+    }
+
+    pub fn find(name: &str) -> Option<usize> {
+        THEMES.iter().position(|theme| theme.name == name)
+    }
+}
+
+impl Style {
+    /// SGR sequence that resets the attributes and applies this style.
+    pub fn sgr(&self, support: ColorSupport) -> String {
+        let mut params = vec!["0".to_string()];
+        if self.bold {
+            params.push("1".to_string());
+        }
+        if self.italic {
+            params.push("3".to_string());
+        }
+        if self.underline {
+            params.push("4".to_string());
+        }
+        if self.reverse {
+            params.push("7".to_string());
+        }
+        if let Some(fg) = self.fg {
+            params.push(color_param(fg, support, false));
+        }
+        if let Some(bg) = self.bg {
+            params.push(color_param(bg, support, true));
+        }
+
+        format!("\x1b[{}m", params.join(";"))
+    }
+}
+
+fn color_param(color: Color, support: ColorSupport, bg: bool) -> String {
+    let color = match (color, support) {
+        (Color::Rgb(r, g, b), ColorSupport::Indexed) => Color::Indexed(rgb_to_indexed(r, g, b)),
+        (Color::Rgb(r, g, b), ColorSupport::Basic) => Color::Ansi(rgb_to_ansi(r, g, b)),
+        (Color::Indexed(n), ColorSupport::Basic) => {
+            let (r, g, b) = indexed_to_rgb(n);
+            Color::Ansi(rgb_to_ansi(r, g, b))
+        }
+        (color, _) => color,
+    };
+
+    match color {
+        Color::Ansi(n) if n < 8 => format!("{}", if bg { 40 } else { 30 } + n),
+        Color::Ansi(n) => format!("{}", if bg { 100 } else { 90 } + (n & 7)),
+        Color::Indexed(n) => format!("{};5;{}", if bg { 48 } else { 38 }, n),
+        Color::Rgb(r, g, b) => format!("{};2;{};{};{}", if bg { 48 } else { 38 }, r, g, b),
+    }
+}
+
+// *** Downgrading ***
+
+/// xterm's default values for the 16 basic colors.
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn rgb_to_indexed(r: u8, g: u8, b: u8) -> u8 {
+    if r == g && g == b {
+        return match r {
+            0..=7 => 16,
+            249..=255 => 231,
+            _ => 232 + ((u16::from(r) - 8) * 24 / 241) as u8,
+        };
+    }
+
+    let level = |v: u8| match v {
+        0..=47 => 0,
+        48..=114 => 1,
+        _ => (v - 35) / 40,
+    };
+    16 + 36 * level(r) + 6 * level(g) + level(b)
+}
+
+fn indexed_to_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => ANSI_RGB[n as usize],
+        16..=231 => {
+            let n = n - 16;
+            (
+                CUBE_LEVELS[(n / 36) as usize],
+                CUBE_LEVELS[(n / 6 % 6) as usize],
+                CUBE_LEVELS[(n % 6) as usize],
+            )
+        }
+        _ => {
+            let v = 8 + 10 * (n - 232);
+            (v, v, v)
+        }
+    }
+}
+
+/// Nearest of the 16 basic colors.
+fn rgb_to_ansi(r: u8, g: u8, b: u8) -> u8 {
+    let dist = |&(cr, cg, cb): &(u8, u8, u8)| {
+        let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+        d(r, cr) + d(g, cg) + d(b, cb)
+    };
+
+    (0..ANSI_RGB.len())
+        .min_by_key(|&i| dist(&ANSI_RGB[i]))
+        .unwrap_or(7) as u8
+}
+
+// *** Bundled themes ***
+
+const fn fg(color: Color) -> Style {
+    Style {
+        fg: Some(color),
+        ..PLAIN
+    }
+}
+
+const fn fg_bg(fg: Color, bg: Color) -> Style {
+    Style {
+        fg: Some(fg),
+        bg: Some(bg),
+        ..PLAIN
+    }
+}
+
+const fn italic(style: Style) -> Style {
+    Style {
+        italic: true,
+        ..style
+    }
+}
+
+const fn bold(style: Style) -> Style {
+    Style {
+        bold: true,
+        ..style
+    }
+}
+
+const PLAIN: Style = Style {
+    fg: None,
+    bg: None,
+    bold: false,
+    italic: false,
+    underline: false,
+    reverse: false,
+};
+
+const REVERSE: Style = Style {
+    reverse: true,
+    ..PLAIN
+};
+
+const SOLARIZED_BASE03: Color = Color::Rgb(0x00, 0x2b, 0x36);
+const SOLARIZED_BASE02: Color = Color::Rgb(0x07, 0x36, 0x42);
+const SOLARIZED_BASE01: Color = Color::Rgb(0x58, 0x6e, 0x75);
+const SOLARIZED_BASE00: Color = Color::Rgb(0x65, 0x7b, 0x83);
+const SOLARIZED_BASE0: Color = Color::Rgb(0x83, 0x94, 0x96);
+const SOLARIZED_BASE1: Color = Color::Rgb(0x93, 0xa1, 0xa1);
+const SOLARIZED_BASE2: Color = Color::Rgb(0xee, 0xe8, 0xd5);
+const SOLARIZED_BASE3: Color = Color::Rgb(0xfd, 0xf6, 0xe3);
+const SOLARIZED_YELLOW: Color = Color::Rgb(0xb5, 0x89, 0x00);
+const SOLARIZED_RED: Color = Color::Rgb(0xdc, 0x32, 0x2f);
+const SOLARIZED_MAGENTA: Color = Color::Rgb(0xd3, 0x36, 0x82);
+const SOLARIZED_CYAN: Color = Color::Rgb(0x2a, 0xa1, 0x98);
+const SOLARIZED_GREEN: Color = Color::Rgb(0x85, 0x99, 0x00);
+
+const GRUVBOX_BG: Color = Color::Rgb(0x28, 0x28, 0x28);
+const GRUVBOX_BG2: Color = Color::Rgb(0x50, 0x49, 0x45);
+const GRUVBOX_FG: Color = Color::Rgb(0xeb, 0xdb, 0xb2);
+const GRUVBOX_GRAY: Color = Color::Rgb(0x92, 0x83, 0x74);
+const GRUVBOX_RED: Color = Color::Rgb(0xfb, 0x49, 0x34);
+const GRUVBOX_GREEN: Color = Color::Rgb(0xb8, 0xbb, 0x26);
+const GRUVBOX_YELLOW: Color = Color::Rgb(0xfa, 0xbd, 0x2f);
+const GRUVBOX_PURPLE: Color = Color::Rgb(0xd3, 0x86, 0x9b);
+const GRUVBOX_ORANGE: Color = Color::Rgb(0xfe, 0x80, 0x19);
+
+pub const THEMES: &[Theme] = &[
+    Theme {
+        name: "default",
+        normal: PLAIN,
+        comment: fg(Color::Ansi(6)),
+        keyword1: fg(Color::Ansi(3)),
+        keyword2: fg(Color::Ansi(2)),
+        string: fg(Color::Ansi(5)),
+        number: fg(Color::Ansi(1)),
+        matched: fg(Color::Ansi(4)),
+        control: REVERSE,
+        selection: REVERSE,
+        status_bar: REVERSE,
+    },
+    Theme {
+        name: "solarized-dark",
+        normal: fg_bg(SOLARIZED_BASE0, SOLARIZED_BASE03),
+        comment: italic(fg_bg(SOLARIZED_BASE01, SOLARIZED_BASE03)),
+        keyword1: fg_bg(SOLARIZED_GREEN, SOLARIZED_BASE03),
+        keyword2: fg_bg(SOLARIZED_YELLOW, SOLARIZED_BASE03),
+        string: fg_bg(SOLARIZED_CYAN, SOLARIZED_BASE03),
+        number: fg_bg(SOLARIZED_MAGENTA, SOLARIZED_BASE03),
+        matched: fg_bg(SOLARIZED_BASE03, SOLARIZED_YELLOW),
+        control: fg_bg(SOLARIZED_BASE03, SOLARIZED_RED),
+        selection: fg_bg(SOLARIZED_BASE1, SOLARIZED_BASE02),
+        status_bar: bold(fg_bg(SOLARIZED_BASE1, SOLARIZED_BASE02)),
+    },
+    Theme {
+        name: "solarized-light",
+        normal: fg_bg(SOLARIZED_BASE00, SOLARIZED_BASE3),
+        comment: italic(fg_bg(SOLARIZED_BASE1, SOLARIZED_BASE3)),
+        keyword1: fg_bg(SOLARIZED_GREEN, SOLARIZED_BASE3),
+        keyword2: fg_bg(SOLARIZED_YELLOW, SOLARIZED_BASE3),
+        string: fg_bg(SOLARIZED_CYAN, SOLARIZED_BASE3),
+        number: fg_bg(SOLARIZED_MAGENTA, SOLARIZED_BASE3),
+        matched: fg_bg(SOLARIZED_BASE3, SOLARIZED_YELLOW),
+        control: fg_bg(SOLARIZED_BASE3, SOLARIZED_RED),
+        selection: fg_bg(SOLARIZED_BASE01, SOLARIZED_BASE2),
+        status_bar: bold(fg_bg(SOLARIZED_BASE01, SOLARIZED_BASE2)),
+    },
+    Theme {
+        name: "gruvbox-dark",
+        normal: fg_bg(GRUVBOX_FG, GRUVBOX_BG),
+        comment: italic(fg_bg(GRUVBOX_GRAY, GRUVBOX_BG)),
+        keyword1: bold(fg_bg(GRUVBOX_RED, GRUVBOX_BG)),
+        keyword2: fg_bg(GRUVBOX_YELLOW, GRUVBOX_BG),
+        string: fg_bg(GRUVBOX_GREEN, GRUVBOX_BG),
+        number: fg_bg(GRUVBOX_PURPLE, GRUVBOX_BG),
+        matched: fg_bg(GRUVBOX_BG, GRUVBOX_ORANGE),
+        control: fg_bg(GRUVBOX_BG, GRUVBOX_RED),
+        selection: fg_bg(GRUVBOX_FG, GRUVBOX_BG2),
+        status_bar: fg_bg(GRUVBOX_FG, GRUVBOX_BG2),
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgb_to_indexed_picks_grey_ramp_or_cube() {
+        for &(rgb, n) in &[
+            ((0, 0, 0), 16),
+            ((7, 7, 7), 16),
+            ((8, 8, 8), 232),
+            ((248, 248, 248), 255),
+            ((249, 249, 249), 231),
+            ((47, 0, 0), 16),
+            ((48, 0, 0), 52),
+            ((114, 0, 0), 52),
+            ((115, 0, 0), 88),
+            ((0, 48, 115), 16 + 6 + 2),
+            ((255, 0, 0), 196),
+        ] {
+            let (r, g, b) = rgb;
+            assert_eq!(rgb_to_indexed(r, g, b), n, "{:?}", rgb);
+        }
+    }
+
+    #[test]
+    fn indexed_to_rgb_covers_the_palette() {
+        assert_eq!(indexed_to_rgb(9), (255, 0, 0));
+        assert_eq!(indexed_to_rgb(16), (0, 0, 0));
+        assert_eq!(indexed_to_rgb(52), (95, 0, 0));
+        assert_eq!(indexed_to_rgb(231), (255, 255, 255));
+        assert_eq!(indexed_to_rgb(232), (8, 8, 8));
+        assert_eq!(indexed_to_rgb(255), (238, 238, 238));
+    }
+
+    #[test]
+    fn colors_are_downgraded_to_what_the_terminal_supports() {
+        use ColorSupport::*;
+        for &(color, support, bg, param) in &[
+            (Color::Ansi(1), TrueColor, false, "31"),
+            (Color::Ansi(1), TrueColor, true, "41"),
+            (Color::Ansi(9), Basic, false, "91"),
+            (Color::Ansi(15), Basic, true, "107"),
+            (Color::Indexed(196), Indexed, false, "38;5;196"),
+            (Color::Indexed(196), Basic, false, "91"),
+            (Color::Indexed(16), Basic, true, "40"),
+            (Color::Rgb(255, 0, 0), TrueColor, false, "38;2;255;0;0"),
+            (Color::Rgb(255, 0, 0), Indexed, true, "48;5;196"),
+            (Color::Rgb(255, 0, 0), Basic, false, "91"),
+            (Color::Rgb(205, 0, 0), Basic, false, "31"),
+            (Color::Rgb(250, 250, 250), Basic, true, "107"),
+            (Color::Rgb(120, 120, 120), Basic, false, "90"),
+        ] {
+            assert_eq!(color_param(color, support, bg), param);
+        }
+    }
+}